@@ -5,14 +5,56 @@ use core::mem::size_of;
 use cobs::{CobsEncoder, decode, decode_in_place};
 use postcard_cobs as cobs;
 
+pub mod stream;
+
 pub fn max_encoding_length(len: usize) -> usize {
     // message length + port bytes + sentinel byte
     cobs::max_encoding_length(len + size_of::<Port>() + 1)
 }
 
+/// The largest message length whose frame is guaranteed to fit in `space`
+/// bytes, i.e. the inverse of [max_encoding_length]. Returns 0 if not even a
+/// single byte of data would fit.
+pub fn max_data_length(space: usize) -> usize {
+    // Start from a guess that always fits, then grow it while the next
+    // length still fits. This takes at most a couple of steps.
+    let mut len = space.saturating_sub(size_of::<Port>() + 2 + (space / 254));
+    while max_encoding_length(len + 1) <= space {
+        len += 1;
+    }
+
+    // Only a frame with no data at all (or not even that) fits
+    if max_encoding_length(len) > space {
+        return 0;
+    }
+    len
+}
+
 // Note: this sort of assumes this is some uN primative type. Thats fine for now.
 pub type Port = u16;
 
+/// Fragmentation
+///
+/// A sender may split a logical message across multiple frames, e.g. when
+/// its outgoing queue only has room for part of it at a time. On the wire,
+/// the high bit of the port field is the "continuation" bit:
+///
+/// * If set, more frames of the same logical message follow
+/// * If clear, this is the last (or only) frame of the logical message
+///
+/// A logical message is reassembled by concatenating the data of all frames
+/// received on the same logical port, up to and including the first frame
+/// without the continuation bit. Frames of a single logical message are never
+/// interleaved with other frames for the same port. Receivers that treat
+/// ports as byte streams (like the kernel) can simply ignore the bit.
+///
+/// This means only the lower 15 bits are available as port numbers. Messages
+/// that fit in a single frame are encoded exactly as before.
+pub const CONTINUATION_BIT: Port = 0x8000;
+
+/// The largest usable logical port number
+pub const MAX_PORT: Port = !CONTINUATION_BIT;
+
 pub struct Message<'a> {
     pub port: Port,
     pub data: &'a [u8],
@@ -24,6 +66,23 @@ pub enum Error {
 }
 
 impl<'a> Message<'a> {
+    /// Create a fragment of a logical message on `port`. If `more` is true,
+    /// the continuation bit will be set.
+    pub fn fragment(port: Port, data: &'a [u8], more: bool) -> Self {
+        let port = if more { port | CONTINUATION_BIT } else { port & MAX_PORT };
+        Self { port, data }
+    }
+
+    /// The logical port of this message, without the continuation bit
+    pub fn logical_port(&self) -> Port {
+        self.port & MAX_PORT
+    }
+
+    /// Do more frames of this logical message follow this one?
+    pub fn is_continued(&self) -> bool {
+        (self.port & CONTINUATION_BIT) != 0
+    }
+
     pub fn encode_to<'b>(&self, dest: &'b mut [u8]) -> Result<&'b [u8], Error> {
         let mut encoder = CobsEncoder::new(dest);
         let port_le = self.port.to_le_bytes();
@@ -96,3 +155,40 @@ impl<'a> Message<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn max_data_length_is_exact() {
+        for space in 0..2048 {
+            let len = max_data_length(space);
+            assert!(max_encoding_length(len + 1) > space, "space {}", space);
+            if len == 0 {
+                continue;
+            }
+
+            // Worst case for COBS: no zero bytes anywhere
+            let data = vec![0xAAu8; len];
+            let mut dest = vec![0u8; space];
+            let msg = Message::fragment(0x7ABC, &data, true);
+            assert!(msg.encode_to(&mut dest).is_ok(), "space {}", space);
+        }
+    }
+
+    #[test]
+    fn max_data_length_at_block_boundaries() {
+        // Nothing fits until there's room for the port, a COBS code byte,
+        // the terminator, and one byte of data
+        assert_eq!(max_data_length(4), 0);
+        assert_eq!(max_data_length(5), 1);
+
+        // Each full block of 254 encoded bytes costs another code byte
+        for space in [257, 258, 259, 510, 511, 512, 4096] {
+            let len = max_data_length(space);
+            assert!(max_encoding_length(len) <= space);
+            assert!(max_encoding_length(len + 1) > space);
+        }
+    }
+}
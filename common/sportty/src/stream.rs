@@ -0,0 +1,277 @@
+//! Decode sportty frames as their bytes arrive
+//!
+//! Collecting a whole frame before decoding it limits a frame to the size of
+//! the buffer, and delays all of it until the last byte arrives. The
+//! [StreamDecoder] instead undoes the COBS encoding a byte at a time, and
+//! hands out decoded data as soon as the frame's port is known, so frames of
+//! any size pass through a small, fixed amount of memory.
+//!
+//! The catch: a frame is only known to be intact once its terminator
+//! arrives. If it turns out to be broken, the data already handed out for it
+//! can't be taken back. Since ports are byte streams, that just means some
+//! garbage (or a gap) in the stream, much like with a physical serial link.
+
+use crate::{Port, MAX_PORT};
+
+/// How a call to [StreamDecoder::feed] ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// All of the input was consumed (or `out` is full), and the current
+    /// frame (if any) isn't complete yet
+    Partial,
+    /// A frame ended, and was intact
+    End,
+    /// A frame ended, but was broken: it ended in the middle of a COBS
+    /// block, or was too short to hold a port. Some of its data may already
+    /// have been handed out.
+    Error,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Decoded<'i, 'o> {
+    /// The input that wasn't consumed yet, which still needs to be fed
+    pub remainder: &'i [u8],
+    /// The logical port of the frame the `data` belongs to, once known
+    pub port: Option<Port>,
+    /// Decoded data of the current frame, from this call
+    pub data: &'o [u8],
+    pub status: Status,
+}
+
+pub struct StreamDecoder {
+    // Encoded bytes left in the current COBS block
+    remaining: u8,
+    // Whether the current COBS block is followed by a zero, which is only
+    // emitted once we know the frame goes on
+    zero_after: bool,
+    // Whether we've seen any byte of the current frame
+    in_frame: bool,
+    // The little-endian port, until both bytes are decoded
+    port_buf: [u8; 2],
+    port_len: usize,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self {
+            remaining: 0,
+            zero_after: false,
+            in_frame: false,
+            port_buf: [0; 2],
+            port_len: 0,
+        }
+    }
+
+    /// Discard any partially decoded frame, e.g. after bytes were lost
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn port(&self) -> Option<Port> {
+        if self.port_len == self.port_buf.len() {
+            Some(Port::from_le_bytes(self.port_buf) & MAX_PORT)
+        } else {
+            None
+        }
+    }
+
+    /// Decode as much of `input` as possible into `out`.
+    ///
+    /// This stops early at the end of each frame (see [Status]), or once
+    /// `out` is full, so call it again with the `remainder` until that is
+    /// empty. The `data` always belongs to a single frame. `out` must not be
+    /// empty.
+    ///
+    /// A terminator with no frame before it (e.g. the zero used to resync
+    /// a link) is skipped silently.
+    pub fn feed<'i, 'o>(&mut self, input: &'i [u8], out: &'o mut [u8]) -> Decoded<'i, 'o> {
+        let mut used = 0;
+
+        for (i, &byte) in input.iter().enumerate() {
+            if byte == 0 {
+                let port = self.port();
+                let status = if !self.in_frame {
+                    // Nothing to end
+                    continue;
+                } else if (self.remaining != 0) || port.is_none() {
+                    Status::Error
+                } else {
+                    Status::End
+                };
+                self.reset();
+
+                return Decoded {
+                    remainder: &input[(i + 1)..],
+                    port,
+                    data: &out[..used],
+                    status,
+                };
+            }
+
+            // A code byte starts a new block, and tells us the previous one
+            // wasn't the last, so its zero is real. A data byte is itself.
+            let is_code = self.remaining == 0;
+            let emits = !is_code || (self.in_frame && self.zero_after);
+
+            // Check for room before changing any state, so we can come back
+            // for this byte next time
+            let port_known = self.port_len == self.port_buf.len();
+            if emits && port_known && (used == out.len()) {
+                return Decoded {
+                    remainder: &input[i..],
+                    port: self.port(),
+                    data: &out[..used],
+                    status: Status::Partial,
+                };
+            }
+
+            let decoded = if is_code {
+                self.remaining = byte - 1;
+                self.zero_after = byte != 0xFF;
+                self.in_frame = true;
+                0
+            } else {
+                self.remaining -= 1;
+                byte
+            };
+
+            if !emits {
+                continue;
+            }
+
+            if port_known {
+                out[used] = decoded;
+                used += 1;
+            } else {
+                self.port_buf[self.port_len] = decoded;
+                self.port_len += 1;
+            }
+        }
+
+        Decoded {
+            remainder: &[],
+            port: self.port(),
+            data: &out[..used],
+            status: Status::Partial,
+        }
+    }
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Message;
+
+    fn encode(port: Port, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; crate::max_encoding_length(data.len())];
+        Message { port, data }.encode_to(&mut buf).ok().unwrap().to_vec()
+    }
+
+    // Feed all of `input`, `chunk` bytes at a time, with an `out` buffer of
+    // `out_len` bytes. Returns everything decoded, and each frame's status.
+    fn decode_all(input: &[u8], chunk: usize, out_len: usize) -> (Vec<(Port, u8)>, Vec<Status>) {
+        let mut dec = StreamDecoder::new();
+        let mut out = vec![0u8; out_len];
+        let mut data = Vec::new();
+        let mut ends = Vec::new();
+
+        for mut window in input.chunks(chunk) {
+            while !window.is_empty() {
+                let res = dec.feed(window, &mut out);
+                if let Some(port) = res.port {
+                    data.extend(res.data.iter().map(|b| (port, *b)));
+                } else {
+                    assert!(res.data.is_empty());
+                }
+                if res.status != Status::Partial {
+                    ends.push(res.status);
+                }
+                window = res.remainder;
+            }
+        }
+        (data, ends)
+    }
+
+    #[test]
+    fn matches_whole_frame_decoding() {
+        // Many COBS blocks, with zeros throughout
+        let msg: Vec<u8> = (0..600u32).map(|i| (i % 7) as u8).collect();
+        let frame = encode(3, &msg);
+
+        let mut copy = frame.clone();
+        let whole = Message::decode_in_place(&mut copy).ok().unwrap();
+        assert_eq!(whole.data, &msg[..]);
+
+        for chunk in [1, 2, 5, 64, frame.len()] {
+            for out_len in [1, 3, 256] {
+                let (data, ends) = decode_all(&frame, chunk, out_len);
+                assert_eq!(ends, [Status::End]);
+                assert!(data.iter().all(|(port, _)| *port == 3));
+                let bytes: Vec<u8> = data.iter().map(|(_, b)| *b).collect();
+                assert_eq!(bytes, msg);
+            }
+        }
+    }
+
+    #[test]
+    fn block_of_254_without_zero() {
+        // With the port, exactly one full (0xFF) block, and no zeros at all
+        let msg = [0xAAu8; 254 - 2];
+        let frame = encode(0x0101, &msg);
+        assert_eq!(frame[0], 0xFF);
+
+        let (data, ends) = decode_all(&frame, 7, 16);
+        assert_eq!(ends, [Status::End]);
+        assert!(data.iter().all(|d| *d == (0x0101, 0xAA)));
+        assert_eq!(data.len(), msg.len());
+    }
+
+    #[test]
+    fn back_to_back_frames_on_different_ports() {
+        let mut input = encode(1, b"hello");
+        input.extend(encode(2 | crate::CONTINUATION_BIT, b"world"));
+        input.extend(encode(2, b""));
+
+        let (data, ends) = decode_all(&input, input.len(), 64);
+        assert_eq!(ends, [Status::End, Status::End, Status::End]);
+        let one: Vec<u8> = data.iter().filter(|(p, _)| *p == 1).map(|(_, b)| *b).collect();
+        let two: Vec<u8> = data.iter().filter(|(p, _)| *p == 2).map(|(_, b)| *b).collect();
+        assert_eq!(one, b"hello");
+        assert_eq!(two, b"world");
+    }
+
+    #[test]
+    fn lone_terminators_are_skipped() {
+        let mut input = vec![0, 0];
+        input.extend(encode(4, b"hi"));
+        let (data, ends) = decode_all(&input, 1, 8);
+        assert_eq!(ends, [Status::End]);
+        assert_eq!(data, [(4, b'h'), (4, b'i')]);
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        // Lose the middle of a frame, so it ends part way through a block
+        let mut input = encode(1, b"abcdef");
+        input.drain(4..6);
+        input.extend(encode(1, b"ok"));
+
+        let (data, ends) = decode_all(&input, 3, 8);
+        assert_eq!(ends, [Status::Error, Status::End]);
+        assert!(data.ends_with(&[(1, b'o'), (1, b'k')]));
+    }
+
+    #[test]
+    fn frame_without_port_is_an_error() {
+        // A single decoded byte: too short for a port
+        let (data, ends) = decode_all(&[0x02, 0x05, 0x00], 3, 8);
+        assert_eq!(ends, [Status::Error]);
+        assert!(data.is_empty());
+    }
+}
@@ -44,7 +44,7 @@ impl SliceMut {
 pub extern "C" fn serial_open_port(port: u16) -> StatusCode {
     match serial::open_port(port) {
         Ok(()) => StatusCode::STATUS_GOOD,
-        Err(()) => StatusCode::STATUS_BAD,
+        Err(_) => StatusCode::STATUS_BAD,
     }
 }
 
@@ -53,7 +53,7 @@ pub extern "C" fn serial_open_port(port: u16) -> StatusCode {
 pub extern "C" fn time_sleep_us(us: u32) -> StatusCode {
     match time::sleep_micros(us) {
         Ok(_) => StatusCode::STATUS_GOOD,
-        Err(()) => StatusCode::STATUS_BAD,
+        Err(_) => StatusCode::STATUS_BAD,
     }
 }
 
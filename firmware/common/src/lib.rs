@@ -1,9 +1,12 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-use core::{sync::atomic::{AtomicPtr, AtomicUsize, Ordering}, ptr::null_mut, marker::PhantomData, arch::asm};
+use core::{sync::atomic::{AtomicPtr, AtomicUsize}, ptr::null_mut, marker::PhantomData};
+#[cfg(target_arch = "arm")]
+use core::sync::atomic::Ordering;
 use serde::{Serialize, Deserialize};
 
 pub mod porcelain;
+pub mod loader;
 
 // NOTE: These symbols are only public so the kernel doesn't have to
 // redefine them. Don't touch.
@@ -33,6 +36,9 @@ pub static SYSCALL_OUT_PTR: AtomicPtr<u8> = AtomicPtr::new(null_mut());
 pub static SYSCALL_OUT_LEN: AtomicUsize = AtomicUsize::new(0);
 
 
+// NOTE: Add new variants to the END of `SysCallRequest` and `SysCallSuccess`,
+// otherwise `SYSCALL_ABI_VERSION` must be bumped.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 #[derive(Serialize, Deserialize)]
 pub enum SysCallRequest<'a> {
     SerialOpenPort {
@@ -46,11 +52,96 @@ pub enum SysCallRequest<'a> {
         port: u16,
         src_buf: SysCallSlice<'a>,
     },
+    SerialSetPortMode {
+        port: u16,
+        mode: PortMode,
+    },
+    SerialSetPortPriority {
+        port: u16,
+        priority: PortPriority,
+    },
+    SerialWaitData {
+        port: u16,
+        // Give up after this many ticks, or wait forever if `None`
+        timeout_ticks: Option<u32>,
+    },
+    SerialReceiveOwned {
+        port: u16,
+    },
+    SerialReleaseOwned {
+        buf: SysCallSlice<'a>,
+    },
     SleepMicros {
         us: u32,
-    }
+    },
+    SleepUntil {
+        tick: u64,
+    },
+    KernelVersion,
+    DeviceIds,
+    Exit {
+        reason: ExitReason,
+    },
+    LowPower {
+        wake_on: WakeSource,
+    },
+    SerialReceiveNoWait {
+        port: u16,
+        dest_buf: SysCallSliceMut<'a>
+    },
+    SerialPump,
+    SerialAvailable {
+        port: u16,
+    },
+    SerialSendSpace {
+        port: u16,
+    },
+    StopwatchStart,
+    StopwatchLap {
+        handle: u64,
+    },
+    SerialSetPortRate {
+        port: u16,
+        bytes_per_sec: u32,
+    },
+    AllocPool {
+        bytes: u32,
+    },
+    FreePool {
+        buf: SysCallSlice<'a>,
+    },
+    SerialListPorts {
+        dest_buf: SysCallSliceMut<'a>,
+    },
+    SerialReleaseAll,
+    SerialLinkState,
+    SleepService {
+        port: u16,
+        us: u32,
+    },
+    Vendor {
+        id: u16,
+        payload: SysCallSlice<'a>,
+        dest_buf: SysCallSliceMut<'a>,
+    },
+    SetStatusLed {
+        control: StatusLed,
+    },
+    SerialGetLineCoding {
+        port: u16,
+    },
+    ResetReason,
+    SetLogLevel {
+        level: LogLevel,
+    },
+    SerialLinkStats,
+    SerialPortStats {
+        port: u16,
+    },
+    BootDefault,
 }
 
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 #[derive(Serialize, Deserialize)]
 pub enum SysCallSuccess<'a> {
     PortOpened,
@@ -60,11 +151,321 @@ pub enum SysCallSuccess<'a> {
     DataSent {
         remainder: Option<SysCallSlice<'a>>,
     },
+    PortModeSet,
+    PortPrioritySet,
+    DataAvailable {
+        bytes: u32,
+    },
+    OwnedDataReceived {
+        buf: Option<SysCallSlice<'a>>,
+    },
+    OwnedDataReleased,
     SleptMicros {
         us: u32,
+    },
+    SleptUntil {
+        tick: u64,
+    },
+    VersionInfo {
+        major: u16,
+        minor: u16,
+        patch: u16,
+        git_hash: SysCallSlice<'a>,
+        build_timestamp: u32,
+    },
+    DeviceIds {
+        device_id: u64,
+        flash_jedec_id: Option<[u8; 3]>,
+    },
+    Woke,
+    Pumped,
+    SendSpace {
+        bytes: u32,
+    },
+    StopwatchStarted {
+        handle: u64,
+    },
+    StopwatchLapped {
+        elapsed_ticks: u64,
+    },
+    PortRateSet,
+    PoolAllocated {
+        buf: SysCallSliceMut<'a>,
+    },
+    PoolFreed,
+    PortsListed {
+        dest_buf: SysCallSliceMut<'a>,
+    },
+    AllReleased {
+        count: u32,
+    },
+    LinkState {
+        state: LinkState,
+        suspends: u32,
+    },
+    SleptService {
+        us: u32,
+        available: u32,
+    },
+    VendorHandled {
+        dest_buf: SysCallSliceMut<'a>,
+    },
+    StatusLedSet,
+    LineCoding {
+        coding: LineCoding,
+    },
+    ResetReason {
+        cause: ResetCause,
+        exit: Option<ExitReason>,
+    },
+    LogLevelSet,
+    LinkStats {
+        stats: LinkStats,
+    },
+    PortStats {
+        stats: PortStats,
+    },
+}
+
+/// How data on the serial link is exchanged with a port
+///
+/// All ports share a single USB serial link, so at most one port may be in
+/// `Raw` mode at a time. While a port is in `Raw` mode, the link is a plain
+/// byte stream (e.g. for a generic serial terminal):
+///
+/// * ALL incoming bytes are delivered to that port
+/// * Data sent on that port is written as-is, without sportty framing
+/// * No other port will receive data, and sends on other ports are refused
+///   (returned as unsent), until it is switched back to `Framed`
+/// * Frames still queued for sending when it switched are discarded
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PortMode {
+    /// Data is sportty framed, and routed by port number (default)
+    Framed,
+    /// Data skips sportty framing entirely
+    Raw,
+}
+
+/// The state of the USB link to the host
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LinkState {
+    /// No host has configured the device (yet), e.g. because the cable
+    /// isn't plugged in, or enumeration is still in progress. Sent data is
+    /// queued, and will be lost if the queue fills up.
+    Detached,
+    /// A host has configured the device, and data is flowing
+    Configured,
+    /// The host suspended the bus (e.g. the host is asleep). Nothing is
+    /// sent or received until it resumes.
+    Suspended,
+}
+
+/// The serial line settings the host asked for, as seen by `SerialGetLineCoding`
+///
+/// USB serial doesn't use these at all: data always goes at USB speed. But
+/// the host sends them anyway (e.g. when a terminal program picks a baud
+/// rate), so an app can use them to tell what the other end expects. Until
+/// the host sets anything, this is the `Default`, 115200-8N1.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LineCoding {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            baud: 115_200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Error counters for the multiplexed serial link, as reported by
+/// `SerialLinkStats`. The counters wrap on overflow.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    /// Frames which failed to decode. If this is non-zero,
+    /// the link is probably dropping or corrupting bytes.
+    pub framing_errors: u32,
+
+    /// Messages (or chunks of raw data) dropped because the destination
+    /// port's queue, or the heap, was full. If this is non-zero, an app
+    /// isn't reading its port fast enough.
+    pub overruns: u32,
+}
+
+/// Who drives the status LED (`led1`)
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum StatusLed {
+    /// The kernel pulses it once a second, to show it is alive (default).
+    /// The kernel takes it back when the application exits.
+    Heartbeat,
+    /// The application took over, and wants it off
+    Off,
+    /// The application took over, and wants it on
+    On,
+}
+
+/// How much the kernel logs (over RTT), as set by `SetLogLevel`
+///
+/// Each level includes all the levels before it. The default is `Info`.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Only things that went wrong, and lost data or can't be recovered
+    Error = 0,
+    /// Also things that look wrong, like dropped frames
+    Warn = 1,
+    /// Also notable events, like booting an application
+    Info = 2,
+    /// Everything, like ports being registered
+    Debug = 3,
+}
+
+/// What may wake the CPU from a `LowPower` (System ON) sleep
+///
+/// Whatever woke the CPU is serviced (e.g. incoming USB data is queued)
+/// before the syscall returns.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum WakeSource {
+    /// Any interrupt. This is currently the only supported wake source.
+    ///
+    /// While USB is connected, this includes the 1ms start-of-frame
+    /// interrupt, so sleeps last at most ~1ms. While disconnected, the
+    /// CPU sleeps until the next USB event or kernel timer interrupt.
+    Interrupt,
+}
+
+/// Why an application exited, which also decides what the kernel does next
+///
+/// The reason is stored in a retained register (GPREGRET), so it can still
+/// be read after a reset. A value of zero means "no exit recorded".
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ExitReason {
+    /// The application finished normally. The kernel halts.
+    Success = 1,
+    /// The application hit an error it couldn't handle. The kernel reboots.
+    Failure = 2,
+    /// The application panicked. The kernel reboots.
+    Panic = 3,
+    /// The application asked for a reboot. The kernel only ever boots its
+    /// built-in default image, so this is also how to get back to a
+    /// known-good (recovery) application, see `BootDefault`.
+    Reboot = 4,
+}
+
+impl ExitReason {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            1 => Some(ExitReason::Success),
+            2 => Some(ExitReason::Failure),
+            3 => Some(ExitReason::Panic),
+            4 => Some(ExitReason::Reboot),
+            _ => None,
+        }
     }
 }
 
+/// What caused the last reset, as reported by `ResetReason`
+///
+/// Together with the [ExitReason] recorded before the reset (if any), this
+/// tells an application whether it was restarted on purpose, or crashed.
+/// For example, a `SoftReset` with an exit reason of `Panic` means the last
+/// application panicked, while no exit reason means the kernel itself did.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ResetCause {
+    /// Power was applied (or came back after a brown-out)
+    PowerOn,
+    /// The reset pin was pulled, e.g. by the reset button or a debug probe
+    PinReset,
+    /// The watchdog timed out
+    Watchdog,
+    /// Software asked for a reset, e.g. after an application exited
+    SoftReset,
+    /// The CPU locked up, e.g. after a fault inside a fault handler
+    Lockup,
+    /// Waking from System OFF, or something else
+    Other,
+}
+
+/// A registered serial port, as reported by `SerialListPorts`.
+///
+/// Entries are postcard encoded back to back, each taking `SIZE` bytes.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct PortInfo {
+    pub port: u16,
+    /// Received messages waiting to be read
+    pub queued_msgs: u16,
+    /// The total size of the queued messages
+    pub queued_bytes: u32,
+}
+
+impl PortInfo {
+    /// The size of an encoded entry. postcard encodes integers at their
+    /// full width.
+    pub const SIZE: usize = 2 + 2 + 4;
+}
+
+/// The state of a single serial port, as reported by `SerialPortStats`
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PortStats {
+    /// Bytes received, and waiting to be read
+    pub queued_bytes: u32,
+    /// The bytes this port may send right now, if it is rate limited
+    pub send_budget: Option<u32>,
+}
+
+/// How urgently outgoing data on a port is sent
+///
+/// Frames from `High` priority ports are sent before any waiting frames
+/// from `Normal` priority ports, but a frame that has started sending is
+/// always finished first. Within a priority, frames go out in the order
+/// they were sent. There is no fairness between priorities: a busy `High`
+/// priority port can delay `Normal` priority ports indefinitely, so keep
+/// `High` for small, urgent messages (like control responses).
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PortPriority {
+    /// The default for all ports
+    Normal,
+    High,
+}
+
 // TODO: using Serde on fields with unsafe side effects is
 // likely a Bad Idea^TM. I'm guessing you could create arbitrary
 // slice references safely, triggering UB.
@@ -73,7 +474,8 @@ pub enum SysCallSuccess<'a> {
 // where the userspace public types DON'T implement serde and private
 // ones that do.
 //
-// For now: yolo.
+// For now: yolo. The kernel does at least check `is_within()` before
+// turning a slice from userspace back into a reference.
 #[derive(Serialize, Deserialize)]
 pub struct SysCallSlice<'a> {
     ptr: u32,
@@ -81,13 +483,51 @@ pub struct SysCallSlice<'a> {
     _pdlt: PhantomData<&'a [u8]>,
 }
 
+/// Is the byte range `[ptr, ptr + len)` entirely within `[start, start + size)`?
+///
+/// Byte slices have no alignment requirement, but even an empty one needs a
+/// non-null pointer, so that is all that's checked for them.
+fn range_within(ptr: u32, len: u32, start: u32, size: u32) -> bool {
+    if ptr == 0 {
+        return false;
+    }
+    if len == 0 {
+        return true;
+    }
+
+    // Widen, so a huge `len` can't wrap around the address space
+    let (ptr, len, start, size) = (ptr as u64, len as u64, start as u64, size as u64);
+    (ptr >= start) && ((ptr + len) <= (start + size))
+}
+
 impl<'a> SysCallSlice<'a> {
+    /// Does this slice lie entirely within the `size` bytes at `start`?
+    ///
+    /// The kernel checks this against the application's RAM before calling
+    /// `to_slice()`, so userspace can't make it read arbitrary memory.
+    pub fn is_within(&self, start: u32, size: u32) -> bool {
+        range_within(self.ptr, self.len, start, size)
+    }
+
     pub unsafe fn to_slice(self) -> &'a [u8] {
         core::slice::from_raw_parts(self.ptr as *const u8, self.len as usize)
     }
 }
 
 impl<'a> SysCallSliceMut<'a> {
+    /// Does this slice lie entirely within the `size` bytes at `start`?
+    /// See [SysCallSlice::is_within].
+    pub fn is_within(&self, start: u32, size: u32) -> bool {
+        range_within(self.ptr, self.len, start, size)
+    }
+
+    /// Does this slice share any bytes with `other`? If so, they can't be
+    /// turned into references at the same time.
+    pub fn overlaps(&self, other: &SysCallSlice<'_>) -> bool {
+        let (a, alen, b, blen) = (self.ptr as u64, self.len as u64, other.ptr as u64, other.len as u64);
+        (alen != 0) && (blen != 0) && (a < (b + blen)) && (b < (a + alen))
+    }
+
     pub unsafe fn to_slice_mut(self) -> &'a mut [u8] {
         core::slice::from_raw_parts_mut(self.ptr as *const u8 as *mut u8, self.len as usize)
     }
@@ -109,6 +549,22 @@ pub struct SysCallSliceMut<'a> {
     _pdlt: PhantomData<&'a mut [u8]>,
 }
 
+// The slices are only printed as their address and length: the data
+// behind them may not even be readable.
+#[cfg(feature = "use-defmt")]
+impl<'a> defmt::Format for SysCallSlice<'a> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "SysCallSlice {{ ptr: {=u32:#010x}, len: {=u32} }}", self.ptr, self.len)
+    }
+}
+
+#[cfg(feature = "use-defmt")]
+impl<'a> defmt::Format for SysCallSliceMut<'a> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "SysCallSliceMut {{ ptr: {=u32:#010x}, len: {=u32} }}", self.ptr, self.len)
+    }
+}
+
 impl<'a> From<&'a [u8]> for SysCallSlice<'a> {
     fn from(sli: &'a [u8]) -> Self {
         Self {
@@ -139,19 +595,201 @@ impl<'a> From<SysCallSliceMut<'a>> for SysCallSlice<'a> {
     }
 }
 
+/// The version of the syscall ABI.
+///
+/// postcard encodes enum variants by index, so reordering (or inserting
+/// into the middle of) `SysCallRequest` or `SysCallSuccess` silently changes
+/// what a given message means. Bump this whenever that happens, or the
+/// encoding of any existing variant changes.
+///
+/// Every syscall request and response starts with this version byte. If the
+/// kernel and application versions don't match, the kernel doesn't handle
+/// the request, and replies with ONLY its own version byte.
+pub const SYSCALL_ABI_VERSION: u8 = 4;
+
+/// The ABI version byte of a syscall buffer didn't match ours
+#[derive(Debug, PartialEq)]
+pub struct AbiMismatch {
+    /// The version found, or `None` if the buffer was empty
+    pub found: Option<u8>,
+}
+
+/// Check and remove the ABI version byte from the start of a syscall
+/// request or response. See [SYSCALL_ABI_VERSION].
+pub fn split_abi_version(buf: &[u8]) -> Result<&[u8], AbiMismatch> {
+    match buf.split_first() {
+        Some((&ver, rest)) if ver == SYSCALL_ABI_VERSION => Ok(rest),
+        Some((&ver, _)) => Err(AbiMismatch { found: Some(ver) }),
+        None => Err(AbiMismatch { found: None }),
+    }
+}
+
+/// Why the kernel refused a syscall
+///
+/// After the ABI version byte, the kernel's response is a serialized
+/// `Result<SysCallSuccess, SysCallError>`.
+///
+/// NOTE: As with `SysCallRequest`, add new variants to the END.
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SysCallError {
+    /// No more specific reason is known
+    Unknown,
+    /// The kernel couldn't decode the request
+    Malformed,
+    /// A slice isn't within the application's RAM (or a pool), or isn't
+    /// one the kernel handed out
+    InvalidSlice,
+    /// The serial driver refused the request, e.g. because the port doesn't
+    /// support it
+    Serial,
+    /// Too many buffers (like pools, or owned receives) are outstanding.
+    /// Give some back first.
+    LimitReached,
+    /// The kernel's heap is busy, or out of space
+    HeapFull,
+    /// An argument is out of range
+    InvalidArgument,
+    /// The response didn't fit in the application's output buffer
+    ResponseTooLarge,
+    /// No handler is registered for a `Vendor` request's `id`
+    NoVendorHandler,
+    /// The handler for a `Vendor` request refused it
+    Vendor,
+    /// The request's timeout passed before it could complete
+    Timeout,
+    /// The port isn't registered
+    NoSuchPort,
+    /// The port is already registered (or has its own USB interface), or
+    /// another port already owns the raw link
+    PortInUse,
+    /// The syscall couldn't be made (e.g. another syscall is in progress),
+    /// or the response didn't make sense. Only reported by [try_syscall],
+    /// never by the kernel.
+    Rejected,
+    /// The kernel speaks a different syscall ABI version. Only reported by
+    /// [try_syscall], never by the kernel.
+    AbiMismatch,
+}
+
+/// Why a syscall failed
+#[derive(Debug, PartialEq)]
+pub enum SysCallFailure {
+    /// The request couldn't be made (e.g. another syscall is in progress),
+    /// or the response didn't make sense
+    Rejected,
+    /// The kernel speaks a different syscall ABI version. This application
+    /// needs to be rebuilt against a matching `common`.
+    AbiMismatch {
+        kernel_version: u8,
+    },
+    /// The kernel refused the request
+    Error(SysCallError),
+}
 
+impl From<SysCallFailure> for SysCallError {
+    fn from(failure: SysCallFailure) -> Self {
+        match failure {
+            SysCallFailure::Rejected => SysCallError::Rejected,
+            SysCallFailure::AbiMismatch { .. } => SysCallError::AbiMismatch,
+            SysCallFailure::Error(err) => err,
+        }
+    }
+}
 
-pub fn try_syscall<'a>(req: SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, ()> {
+/// Make a syscall to the kernel, reporting why it failed, if it did.
+///
+/// See [try_syscall] for the threading model.
+pub fn try_syscall_detailed<'a>(req: SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, SysCallFailure> {
     let mut inp_buf = [0u8; 128];
     let mut out_buf = [0u8; 128];
-    let iused = postcard::to_slice(&req, &mut inp_buf).map_err(drop)?;
-    let oused = raw_syscall(iused, &mut out_buf)?;
-    let result = postcard::from_bytes(oused).map_err(drop)?;
-    Ok(result)
+    inp_buf[0] = SYSCALL_ABI_VERSION;
+    let iused = postcard::to_slice(&req, &mut inp_buf[1..])
+        .map_err(|_| SysCallFailure::Rejected)?
+        .len();
+    let oused = raw_syscall(&inp_buf[..(iused + 1)], &mut out_buf)
+        .map_err(|_| SysCallFailure::Rejected)?;
+    let payload = split_abi_version(oused).map_err(|mm| SysCallFailure::AbiMismatch {
+        kernel_version: mm.found.unwrap_or(0),
+    })?;
+    let result: Result<SysCallSuccess<'a>, SysCallError> = postcard::from_bytes(payload)
+        .map_err(|_| SysCallFailure::Rejected)?;
+    result.map_err(SysCallFailure::Error)
+}
+
+/// Make a syscall to the kernel.
+///
+/// Threading model: syscalls may ONLY be made from thread mode (e.g. the
+/// main loop of an application), never from an interrupt or exception
+/// handler. There is a single set of bridge fields, so only one request can
+/// be in flight at a time, and an interrupt issuing a syscall while thread
+/// mode is mid-syscall would corrupt it. Calling this from handler mode
+/// panics, rather than silently clobbering the bridge.
+///
+/// Use [try_syscall_detailed] to also get the kernel's version on an ABI
+/// mismatch.
+pub fn try_syscall<'a>(req: SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, SysCallError> {
+    try_syscall_detailed(req).map_err(SysCallError::from)
+}
+
+// There's no kernel to call when built for the host (e.g. for host tools),
+// only the shared types are useful there.
+#[cfg(all(not(target_arch = "arm"), not(test)))]
+fn raw_syscall<'i, 'o>(_input: &'i [u8], _output: &'o mut [u8]) -> Result<&'o mut [u8], ()> {
+    Err(())
+}
+
+// In host tests, syscalls go to a fake kernel instead. See `test_shim`.
+#[cfg(all(not(target_arch = "arm"), test))]
+use test_shim::raw_syscall;
+
+/// A stand-in for the kernel, so the porcelain can be tested on the host.
+///
+/// Requests still go through the full encode/decode path, and are handed to
+/// the handler set (per test thread) with [test_shim::set_handler].
+#[cfg(all(not(target_arch = "arm"), test))]
+pub(crate) mod test_shim {
+    use super::*;
+    use std::{boxed::Box, cell::RefCell};
+
+    type Handler = Box<dyn FnMut(SysCallRequest<'_>) -> Result<SysCallSuccess<'static>, SysCallError>>;
+
+    std::thread_local! {
+        static HANDLER: RefCell<Option<Handler>> = RefCell::new(None);
+    }
+
+    pub fn set_handler<F>(handler: F)
+    where
+        F: FnMut(SysCallRequest<'_>) -> Result<SysCallSuccess<'static>, SysCallError> + 'static,
+    {
+        HANDLER.with(|h| *h.borrow_mut() = Some(Box::new(handler)));
+    }
+
+    /// Remove the handler, as if there were no kernel at all
+    pub fn raw_reset() {
+        HANDLER.with(|h| *h.borrow_mut() = None);
+    }
+
+    pub fn raw_syscall<'i, 'o>(input: &'i [u8], output: &'o mut [u8]) -> Result<&'o mut [u8], ()> {
+        let payload = split_abi_version(input).map_err(drop)?;
+        let req = postcard::from_bytes(payload).map_err(drop)?;
+        let resp = HANDLER.with(|h| h.borrow_mut().as_mut().map(|h| h(req))).ok_or(())?;
+
+        output[0] = SYSCALL_ABI_VERSION;
+        let used = postcard::to_slice(&resp, &mut output[1..]).map_err(drop)?.len();
+        Ok(&mut output[..(used + 1)])
+    }
 }
 
 // TODO: This is a userspace (and idle?) thing...
+#[cfg(target_arch = "arm")]
 fn raw_syscall<'i, 'o>(input: &'i [u8], output: &'o mut [u8]) -> Result<&'o mut [u8], ()> {
+    // See the threading model described on `try_syscall`.
+    assert!(
+        in_thread_mode(),
+        "syscalls may only be made from thread mode, not from an interrupt",
+    );
+
     let in_ptr = input.as_ptr() as *mut u8;
 
     // Try to atomically swap the in ptr for our input parameter. If this fails,
@@ -178,7 +816,7 @@ fn raw_syscall<'i, 'o>(input: &'i [u8], output: &'o mut [u8]) -> Result<&'o mut
     SYSCALL_OUT_LEN.store(output.len(), Ordering::SeqCst);
 
     unsafe {
-        asm!("svc 0");
+        core::arch::asm!("svc 0");
     }
 
     // Now we need to grab the output length, then reset all fields.
@@ -194,3 +832,157 @@ fn raw_syscall<'i, 'o>(input: &'i [u8], output: &'o mut [u8]) -> Result<&'o mut
         Ok(&mut output[..new_out_len])
     }
 }
+
+/// Are we in thread mode? The IPSR holds the active exception number,
+/// which is zero in thread mode.
+#[cfg(target_arch = "arm")]
+#[inline(always)]
+fn in_thread_mode() -> bool {
+    let ipsr: u32;
+    unsafe {
+        core::arch::asm!("mrs {}, IPSR", out(reg) ipsr, options(nomem, nostack, preserves_flags));
+    }
+    (ipsr & 0x1FF) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching_version_is_stripped() {
+        let buf = [SYSCALL_ABI_VERSION, 1, 2, 3];
+        assert_eq!(split_abi_version(&buf), Ok(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn bumped_version_is_a_mismatch() {
+        let newer = SYSCALL_ABI_VERSION.wrapping_add(1);
+        let buf = [newer, 1, 2, 3];
+        assert_eq!(split_abi_version(&buf), Err(AbiMismatch { found: Some(newer) }));
+    }
+
+    #[test]
+    fn mismatch_reply_is_only_the_version() {
+        let older = SYSCALL_ABI_VERSION.wrapping_sub(1);
+        assert_eq!(split_abi_version(&[older]), Err(AbiMismatch { found: Some(older) }));
+    }
+
+    #[test]
+    fn empty_buffer_is_a_mismatch() {
+        assert_eq!(split_abi_version(&[]), Err(AbiMismatch { found: None }));
+    }
+
+    fn slice(ptr: u32, len: u32) -> SysCallSlice<'static> {
+        SysCallSlice { ptr, len, _pdlt: PhantomData }
+    }
+
+    #[test]
+    fn slice_inside_region_is_within() {
+        assert!(slice(0x2000_0000, 16).is_within(0x2000_0000, 0x2_0000));
+        assert!(slice(0x2001_FFF0, 16).is_within(0x2000_0000, 0x2_0000));
+    }
+
+    #[test]
+    fn slice_crossing_region_end_is_rejected() {
+        assert!(!slice(0x2001_FFF1, 16).is_within(0x2000_0000, 0x2_0000));
+        assert!(!slice(0x1FFF_FFFF, 1).is_within(0x2000_0000, 0x2_0000));
+    }
+
+    #[test]
+    fn slice_wrapping_address_space_is_rejected() {
+        assert!(!slice(0x2000_0010, u32::MAX).is_within(0x2000_0000, 0x2_0000));
+    }
+
+    #[test]
+    fn empty_slice_only_needs_non_null() {
+        assert!(slice(1, 0).is_within(0x2000_0000, 0x2_0000));
+        assert!(!slice(0, 0).is_within(0x2000_0000, 0x2_0000));
+    }
+
+    #[test]
+    fn overlapping_slices() {
+        let mutable = |ptr, len| SysCallSliceMut { ptr, len, _pdlt: PhantomData };
+        assert!(mutable(0x2000_0000, 16).overlaps(&slice(0x2000_000F, 16)));
+        assert!(mutable(0x2000_0010, 16).overlaps(&slice(0x2000_0000, 17)));
+        assert!(!mutable(0x2000_0000, 16).overlaps(&slice(0x2000_0010, 16)));
+        assert!(!mutable(0x2000_0000, 0).overlaps(&slice(0x2000_0000, 16)));
+    }
+
+    const ALL_ERRORS: [SysCallError; 15] = [
+        SysCallError::Unknown,
+        SysCallError::Malformed,
+        SysCallError::InvalidSlice,
+        SysCallError::Serial,
+        SysCallError::LimitReached,
+        SysCallError::HeapFull,
+        SysCallError::InvalidArgument,
+        SysCallError::ResponseTooLarge,
+        SysCallError::NoVendorHandler,
+        SysCallError::Vendor,
+        SysCallError::Timeout,
+        SysCallError::NoSuchPort,
+        SysCallError::PortInUse,
+        SysCallError::Rejected,
+        SysCallError::AbiMismatch,
+    ];
+
+    #[test]
+    fn error_responses_round_trip() {
+        for err in ALL_ERRORS {
+            let resp: Result<SysCallSuccess<'_>, SysCallError> = Err(err);
+            let mut buf = [0u8; 8];
+            let used = postcard::to_slice(&resp, &mut buf).unwrap();
+            let back: Result<SysCallSuccess<'_>, SysCallError> = postcard::from_bytes(used).unwrap();
+            assert_eq!(back.err(), Some(err));
+        }
+    }
+
+    #[test]
+    fn kernel_errors_reach_the_caller() {
+        for err in ALL_ERRORS {
+            test_shim::set_handler(move |_| Err(err));
+            let res = try_syscall_detailed(SysCallRequest::SerialPump);
+            assert_eq!(res.err(), Some(SysCallFailure::Error(err)));
+        }
+    }
+
+    #[test]
+    fn missing_kernel_is_rejected() {
+        test_shim::raw_reset();
+        let res = try_syscall_detailed(SysCallRequest::SerialPump);
+        assert_eq!(res.err(), Some(SysCallFailure::Rejected));
+    }
+
+    #[test]
+    fn port_info_round_trip() {
+        let info = PortInfo { port: 0x1234, queued_msgs: 3, queued_bytes: 0x0102_0304 };
+        let mut buf = [0u8; PortInfo::SIZE];
+        let used = postcard::to_slice(&info, &mut buf).unwrap().len();
+        assert_eq!(&buf[..used], &[0x34, 0x12, 3, 0, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(postcard::from_bytes::<PortInfo>(&buf[..used]).unwrap(), info);
+    }
+
+    #[test]
+    fn port_info_size() {
+        let info = PortInfo { port: u16::MAX, queued_msgs: u16::MAX, queued_bytes: u32::MAX };
+        let mut buf = [0u8; 16];
+        let used = postcard::to_slice(&info, &mut buf).unwrap().len();
+        assert_eq!(used, PortInfo::SIZE);
+    }
+
+    #[test]
+    fn versioned_request_round_trip() {
+        let mut buf = [0u8; 32];
+        buf[0] = SYSCALL_ABI_VERSION;
+        let used = postcard::to_slice(&SysCallRequest::SleepMicros { us: 1234 }, &mut buf[1..])
+            .unwrap()
+            .len();
+
+        let payload = split_abi_version(&buf[..(used + 1)]).unwrap();
+        match postcard::from_bytes(payload).unwrap() {
+            SysCallRequest::SleepMicros { us } => assert_eq!(us, 1234),
+            _ => panic!("wrong request"),
+        }
+    }
+}
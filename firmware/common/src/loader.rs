@@ -0,0 +1,242 @@
+//! The application image header
+//!
+//! Every application image starts with this header, placed there by the
+//! userspace linker script. It is shared between the kernel (which loads
+//! images) and host tools (which prepare them).
+//!
+//! All fields are little-endian `u32`s, in this order:
+//!
+//! | Offset | Field             | Notes                               |
+//! | :---   | :---              | :---                                |
+//! | 0x00   | `syscall_in_ptr`  | Bridge, must be zero in the image   |
+//! | 0x04   | `syscall_in_len`  | Bridge, must be zero in the image   |
+//! | 0x08   | `syscall_out_ptr` | Bridge, must be zero in the image   |
+//! | 0x0C   | `syscall_out_len` | Bridge, must be zero in the image   |
+//! | 0x10   | `etext`           |                                     |
+//! | 0x14   | `srodata`         |                                     |
+//! | 0x18   | `sdata`           |                                     |
+//! | 0x1C   | `edata`           |                                     |
+//! | 0x20   | `sbss`            |                                     |
+//! | 0x24   | `ebss`            |                                     |
+//! | 0x28   | `stack_start`     |                                     |
+//! | 0x2C   | `entry_point`     | Thumb address (lowest bit set)      |
+
+use core::ops::Range;
+
+/// An application image header, not including the syscall bridge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub etext: u32,
+    pub srodata: u32,
+    pub sdata: u32,
+    pub edata: u32,
+    pub sbss: u32,
+    pub ebss: u32,
+    pub stack_start: u32,
+    pub entry_point: u32,
+}
+
+impl Header {
+    /// The size of the header in an image, in bytes
+    pub const SIZE: usize = 12 * 4;
+
+    // The four syscall bridge words come first
+    const BRIDGE_WORDS: usize = 4;
+
+    /// Serialize the header, with an empty syscall bridge
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        let words = self.words();
+        let chunks = out.chunks_exact_mut(4).skip(Self::BRIDGE_WORDS);
+        for (chunk, word) in chunks.zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    /// Parse the header from the start of an image.
+    ///
+    /// This only checks that the header is present and the syscall bridge is
+    /// empty. It does NOT check that the addresses are sensible for any
+    /// particular memory layout, which is up to the loader.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ()> {
+        let bytes = bytes.get(..Self::SIZE).ok_or(())?;
+        let mut words = [0u32; 12];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(chunk);
+            *word = u32::from_le_bytes(buf);
+        }
+
+        // Make sure all of the bridge values are zero. If they are not,
+        // it's a hint the data may be malformed.
+        let (bridge, hdr) = words.split_at(Self::BRIDGE_WORDS);
+        if !bridge.iter().all(|w| *w == 0) {
+            return Err(());
+        }
+
+        Ok(Self {
+            etext: hdr[0],
+            srodata: hdr[1],
+            sdata: hdr[2],
+            edata: hdr[3],
+            sbss: hdr[4],
+            ebss: hdr[5],
+            stack_start: hdr[6],
+            entry_point: hdr[7],
+        })
+    }
+
+    fn words(&self) -> [u32; 8] {
+        [
+            self.etext,
+            self.srodata,
+            self.sdata,
+            self.edata,
+            self.sbss,
+            self.ebss,
+            self.stack_start,
+            self.entry_point,
+        ]
+    }
+}
+
+/// Why a loaded application can't be started
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum LaunchError {
+    /// The initial stack pointer is outside of application RAM
+    StackOutOfRange,
+    /// The initial stack pointer isn't 8-byte aligned, as the AAPCS requires
+    StackMisaligned,
+    /// The entry point is outside of the loaded .text
+    EntryOutOfRange,
+    /// The entry point doesn't have the thumb bit set
+    EntryNotThumb,
+}
+
+/// Check that an application's initial stack pointer and entry point are
+/// safe to jump to, given the application RAM range `ram` and the end of
+/// its loaded .text, `text_end`.
+pub fn check_launch(
+    stack_start: u32,
+    entry_point: u32,
+    text_end: u32,
+    ram: Range<u32>,
+) -> Result<(), LaunchError> {
+    // The stack is full descending, so it may start at the very end
+    let sp = stack_start;
+    if (sp <= ram.start) || (sp > ram.end) {
+        return Err(LaunchError::StackOutOfRange);
+    }
+    if !sp.is_multiple_of(8) {
+        return Err(LaunchError::StackMisaligned);
+    }
+
+    if (entry_point & 1) == 0 {
+        return Err(LaunchError::EntryNotThumb);
+    }
+    let entry = entry_point & !1;
+    if (entry < ram.start) || (entry >= text_end) {
+        return Err(LaunchError::EntryOutOfRange);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_launch, Header, LaunchError};
+
+    fn example() -> Header {
+        Header {
+            etext: 0x2000_1000,
+            srodata: 0x2000_1000,
+            sdata: 0x2000_8000,
+            edata: 0x2000_8010,
+            sbss: 0x2000_8010,
+            ebss: 0x2000_8100,
+            stack_start: 0x2002_0000,
+            entry_point: 0x2000_0031,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let hdr = example();
+        let bytes = hdr.to_bytes();
+        assert_eq!(Header::parse(&bytes), Ok(hdr));
+    }
+
+    #[test]
+    fn layout_is_little_endian_after_bridge() {
+        let bytes = example().to_bytes();
+        assert_eq!(&bytes[..16], &[0u8; 16]);
+        assert_eq!(&bytes[0x10..0x14], &[0x00, 0x10, 0x00, 0x20]);
+        assert_eq!(&bytes[0x2C..0x30], &[0x31, 0x00, 0x00, 0x20]);
+    }
+
+    #[test]
+    fn parse_ignores_trailing_image() {
+        let mut image = [0xAAu8; 128];
+        image[..Header::SIZE].copy_from_slice(&example().to_bytes());
+        assert_eq!(Header::parse(&image), Ok(example()));
+    }
+
+    #[test]
+    fn short_input_is_rejected() {
+        let bytes = example().to_bytes();
+        assert_eq!(Header::parse(&bytes[..Header::SIZE - 1]), Err(()));
+    }
+
+    #[test]
+    fn nonzero_bridge_is_rejected() {
+        let mut bytes = example().to_bytes();
+        bytes[0x08] = 1;
+        assert_eq!(Header::parse(&bytes), Err(()));
+    }
+
+    const RAM: core::ops::Range<u32> = 0x2000_0000..0x2002_0000;
+
+    fn launch(stack_start: u32, entry_point: u32) -> Result<(), LaunchError> {
+        let hdr = example();
+        check_launch(stack_start, entry_point, hdr.etext, RAM)
+    }
+
+    #[test]
+    fn sane_image_launches() {
+        let hdr = example();
+        assert_eq!(launch(hdr.stack_start, hdr.entry_point), Ok(()));
+        assert_eq!(launch(0x2001_0000, 0x2000_0001), Ok(()));
+    }
+
+    #[test]
+    fn stack_out_of_range_is_rejected() {
+        let entry = example().entry_point;
+        assert_eq!(launch(RAM.start, entry), Err(LaunchError::StackOutOfRange));
+        assert_eq!(launch(RAM.end + 8, entry), Err(LaunchError::StackOutOfRange));
+        assert_eq!(launch(0, entry), Err(LaunchError::StackOutOfRange));
+    }
+
+    #[test]
+    fn misaligned_stack_is_rejected() {
+        let entry = example().entry_point;
+        assert_eq!(launch(RAM.end - 4, entry), Err(LaunchError::StackMisaligned));
+        assert_eq!(launch(RAM.end - 1, entry), Err(LaunchError::StackMisaligned));
+    }
+
+    #[test]
+    fn entry_without_thumb_bit_is_rejected() {
+        let sp = example().stack_start;
+        assert_eq!(launch(sp, 0x2000_0030), Err(LaunchError::EntryNotThumb));
+    }
+
+    #[test]
+    fn entry_out_of_range_is_rejected() {
+        let hdr = example();
+        let sp = hdr.stack_start;
+        assert_eq!(launch(sp, 0x1FFF_FFF1), Err(LaunchError::EntryOutOfRange));
+        assert_eq!(launch(sp, hdr.etext | 1), Err(LaunchError::EntryOutOfRange));
+        assert_eq!(launch(sp, 0x2001_0001), Err(LaunchError::EntryOutOfRange));
+    }
+}
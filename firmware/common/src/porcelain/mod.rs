@@ -1,42 +1,330 @@
-use crate::{SysCallRequest, SysCallSuccess, try_syscall};
+use crate::{SysCallRequest, SysCallSuccess, PortMode, PortPriority, PortInfo, PortStats, ExitReason, ResetCause, LogLevel, WakeSource, LinkState, LinkStats, StatusLed, LineCoding, SysCallError, SysCallFailure, try_syscall, try_syscall_detailed};
 
 pub mod serial {
 
     use super::*;
+    use core::ops::{Deref, DerefMut};
 
-    pub fn open_port(port: u16) -> Result<(), ()> {
+    /// A message received with [read_port_owned], lent to us by the kernel.
+    ///
+    /// The data lives in the kernel's heap, and is handed back to the kernel
+    /// (which frees it) when this is dropped. The kernel only lends out a
+    /// small number of these at a time, so don't hold on to them.
+    pub struct OwnedRecv {
+        data: &'static [u8],
+    }
+
+    impl Deref for OwnedRecv {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            self.data
+        }
+    }
+
+    impl Drop for OwnedRecv {
+        fn drop(&mut self) {
+            let req = SysCallRequest::SerialReleaseOwned { buf: self.data.into() };
+            // If this fails, the kernel keeps the buffer, and it is leaked.
+            try_syscall(req).ok();
+        }
+    }
+
+    /// Receive the next queued message on `port` without copying it.
+    ///
+    /// Returns `Ok(None)` if nothing is queued. Unlike [read_port], this
+    /// works a whole queued chunk at a time. Incoming data is queued in
+    /// chunks as it is decoded, so a large message may span several.
+    pub fn read_port_owned(port: u16) -> Result<Option<OwnedRecv>, SysCallError> {
+        let req = SysCallRequest::SerialReceiveOwned { port };
+
+        match try_syscall(req)? {
+            SysCallSuccess::OwnedDataReceived { buf: Some(buf) } => {
+                // The kernel keeps this allocation alive until we release it
+                let data = unsafe { buf.to_slice() };
+                Ok(Some(OwnedRecv { data }))
+            }
+            SysCallSuccess::OwnedDataReceived { buf: None } => Ok(None),
+            _ => Err(SysCallError::Rejected),
+        }
+    }
+
+    pub fn open_port(port: u16) -> Result<(), SysCallError> {
         let req = SysCallRequest::SerialOpenPort { port };
 
         if let SysCallSuccess::PortOpened = try_syscall(req)? {
             Ok(())
         } else {
-            Err(())
+            Err(SysCallError::Rejected)
         }
     }
 
-    pub fn read_port(port: u16, data: &mut [u8]) -> Result<&mut [u8], ()> {
+    /// Select how incoming data is delivered to `port`. See [PortMode]
+    /// for details. All ports start in [PortMode::Framed].
+    pub fn set_port_mode(port: u16, mode: PortMode) -> Result<(), SysCallError> {
+        let req = SysCallRequest::SerialSetPortMode { port, mode };
+
+        if let SysCallSuccess::PortModeSet = try_syscall(req)? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Select how urgently outgoing data on `port` is sent. See
+    /// [PortPriority] for details. All ports start at [PortPriority::Normal].
+    pub fn set_port_priority(port: u16, priority: PortPriority) -> Result<(), SysCallError> {
+        let req = SysCallRequest::SerialSetPortPriority { port, priority };
+
+        if let SysCallSuccess::PortPrioritySet = try_syscall(req)? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Limit `port` to sending `bytes_per_sec` on average, so it can't
+    /// starve other ports sharing the link. A port that has been quiet may
+    /// send a burst of up to one second's worth. Over the limit, sends
+    /// return an unsent remainder, just like when the link is busy.
+    ///
+    /// A rate of 0 removes the limit, which is the default. This has no
+    /// effect on ports with their own USB interface.
+    pub fn set_port_rate(port: u16, bytes_per_sec: u32) -> Result<(), SysCallError> {
+        let req = SysCallRequest::SerialSetPortRate { port, bytes_per_sec };
+
+        if let SysCallSuccess::PortRateSet = try_syscall(req)? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// The most ports [list_ports] can report
+    pub const MAX_LISTED_PORTS: usize = 16;
+
+    /// Fill `out` with the currently registered ports (including port 0),
+    /// returning how many there are. Ports that don't fit are left out.
+    pub fn list_ports(out: &mut [PortInfo]) -> Result<usize, SysCallError> {
+        let mut buf = [0u8; PortInfo::SIZE * MAX_LISTED_PORTS];
+        let max = out.len().min(MAX_LISTED_PORTS) * PortInfo::SIZE;
+        let req = SysCallRequest::SerialListPorts { dest_buf: (&mut buf[..max]).into() };
+
+        if let SysCallSuccess::PortsListed { dest_buf } = try_syscall(req)? {
+            let used = (dest_buf.len as usize).min(max);
+            let mut rest = &buf[..used];
+            let mut count = 0;
+            for info in out.iter_mut() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (entry, remaining) = postcard::take_from_bytes(rest)
+                    .map_err(|_| SysCallError::Rejected)?;
+                *info = entry;
+                rest = remaining;
+                count += 1;
+            }
+            Ok(count)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Release every port except port 0, e.g. to recover from an app that
+    /// exited without closing its ports. Returns how many were released.
+    pub fn release_all() -> Result<u32, SysCallError> {
+        if let SysCallSuccess::AllReleased { count } = try_syscall(SysCallRequest::SerialReleaseAll)? {
+            Ok(count)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Block until data is available on `port`, without busy-polling
+    /// `read_port`. Returns the number of bytes ready to be read.
+    pub fn wait_data(port: u16) -> Result<usize, SysCallError> {
+        let req = SysCallRequest::SerialWaitData { port, timeout_ticks: None };
+
+        if let SysCallSuccess::DataAvailable { bytes } = try_syscall(req)? {
+            Ok(bytes as usize)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Like [wait_data], but give up after `ticks` (microseconds).
+    ///
+    /// Returns `Ok(None)` if no data showed up in time.
+    pub fn wait_data_timeout(port: u16, ticks: u32) -> Result<Option<usize>, SysCallError> {
+        let req = SysCallRequest::SerialWaitData { port, timeout_ticks: Some(ticks) };
+
+        match try_syscall_detailed(req) {
+            Ok(SysCallSuccess::DataAvailable { bytes }) => Ok(Some(bytes as usize)),
+            Ok(_) => Err(SysCallError::Rejected),
+            Err(SysCallFailure::Error(SysCallError::Timeout)) => Ok(None),
+            Err(failure) => Err(failure.into()),
+        }
+    }
+
+    pub fn read_port(port: u16, data: &mut [u8]) -> Result<&mut [u8], SysCallError> {
+        let max = data.len();
         let req = SysCallRequest::SerialReceive {
             port,
             dest_buf: data.as_mut().into(),
         };
 
-        let resp = try_syscall(req)?;
+        let used = received_len(try_syscall(req)?, max)?;
+        Ok(&mut data[..used])
+    }
 
-        if let SysCallSuccess::DataReceived { dest_buf } = resp {
-            let dblen = dest_buf.len as usize;
+    /// Like [read_port], but only returns data that has already been decoded
+    /// and queued for `port`. Use [pump] to decode new input for all ports.
+    pub fn read_port_nowait(port: u16, data: &mut [u8]) -> Result<&mut [u8], SysCallError> {
+        let max = data.len();
+        let req = SysCallRequest::SerialReceiveNoWait {
+            port,
+            dest_buf: data.as_mut().into(),
+        };
 
-            if dblen <= data.len() {
-                Ok(&mut data[..dblen])
-            } else {
-                Err(())
+        let used = received_len(try_syscall(req)?, max)?;
+        Ok(&mut data[..used])
+    }
+
+    /// How much of a `max` byte buffer a `SerialReceive*` request filled in
+    fn received_len(resp: SysCallSuccess, max: usize) -> Result<usize, SysCallError> {
+        match resp {
+            SysCallSuccess::DataReceived { dest_buf } if (dest_buf.len as usize) <= max => {
+                Ok(dest_buf.len as usize)
             }
-        } else {
             // Unexpected syscall response!
-            Err(())
+            _ => Err(SysCallError::Rejected),
+        }
+    }
+
+    /// Decode all newly received serial data, and queue it for its ports
+    pub fn pump() -> Result<(), SysCallError> {
+        if let SysCallSuccess::Pumped = try_syscall(SysCallRequest::SerialPump)? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// The number of bytes ready to be read from `port` right now
+    pub fn available(port: u16) -> Result<usize, SysCallError> {
+        let req = SysCallRequest::SerialAvailable { port };
+
+        if let SysCallSuccess::DataAvailable { bytes } = try_syscall(req)? {
+            Ok(bytes as usize)
+        } else {
+            Err(SysCallError::Rejected)
         }
     }
 
-    pub fn write_port(port: u16, data: &[u8]) -> Result<Option<&[u8]>, ()> {
+    /// A lower bound on the number of bytes [write_port] would accept on
+    /// `port` right now
+    pub fn send_space(port: u16) -> Result<usize, SysCallError> {
+        let req = SysCallRequest::SerialSendSpace { port };
+
+        if let SysCallSuccess::SendSpace { bytes } = try_syscall(req)? {
+            Ok(bytes as usize)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// The state of the USB link, and how many times the host has suspended
+    /// it since boot. Comparing the count between calls tells whether a
+    /// suspend/resume happened in the meantime.
+    pub fn link_state() -> Result<(LinkState, u32), SysCallError> {
+        if let SysCallSuccess::LinkState { state, suspends } = try_syscall(SysCallRequest::SerialLinkState)? {
+            Ok((state, suspends))
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// How much data is waiting on `port`, and how much it may send right
+    /// now if it is rate limited (see [set_port_rate]). See [PortStats].
+    pub fn port_stats(port: u16) -> Result<PortStats, SysCallError> {
+        if let SysCallSuccess::PortStats { stats } = try_syscall(SysCallRequest::SerialPortStats { port })? {
+            Ok(stats)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Error counters for the multiplexed serial link. See [LinkStats].
+    pub fn link_stats() -> Result<LinkStats, SysCallError> {
+        if let SysCallSuccess::LinkStats { stats } = try_syscall(SysCallRequest::SerialLinkStats)? {
+            Ok(stats)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// The line settings (baud rate, etc.) the host last set for `port`'s
+    /// serial interface, or 115200-8N1 if it hasn't set any. See [LineCoding].
+    ///
+    /// All multiplexed ports share one interface, so they all report the same.
+    pub fn line_coding(port: u16) -> Result<LineCoding, SysCallError> {
+        let req = SysCallRequest::SerialGetLineCoding { port };
+        if let SysCallSuccess::LineCoding { coding } = try_syscall(req)? {
+            Ok(coding)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Why a `try_*` serial operation didn't complete
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum TryError {
+        /// Nothing to receive, or no room to send, right now. Try again later.
+        WouldBlock,
+        /// The syscall failed, e.g. because the port isn't open
+        Failed(SysCallError),
+    }
+
+    impl From<SysCallError> for TryError {
+        fn from(err: SysCallError) -> Self {
+            TryError::Failed(err)
+        }
+    }
+
+    /// Like [read_port], but returns [TryError::WouldBlock] instead of an
+    /// empty slice when there is nothing to receive.
+    ///
+    /// Neither form ever waits for data (use [wait_data] for that), but
+    /// this one makes "no data yet" an explicit case, so a single loop can
+    /// service several ports, moving on from any port that isn't ready.
+    pub fn try_recv(port: u16, data: &mut [u8]) -> Result<&mut [u8], TryError> {
+        if available(port)? == 0 {
+            return Err(TryError::WouldBlock);
+        }
+
+        match read_port(port, data)? {
+            [] => Err(TryError::WouldBlock),
+            used => Ok(used),
+        }
+    }
+
+    /// Like [write_port], but returns [TryError::WouldBlock] when there is
+    /// no room to send any of `data`.
+    ///
+    /// As with [write_port], a partial send gives back the unsent remainder,
+    /// which should be retried later.
+    pub fn try_send(port: u16, data: &[u8]) -> Result<Option<&[u8]>, TryError> {
+        if !data.is_empty() && send_space(port)? == 0 {
+            return Err(TryError::WouldBlock);
+        }
+
+        match write_port(port, data)? {
+            Some(rem) if rem.len() == data.len() => Err(TryError::WouldBlock),
+            rem => Ok(rem),
+        }
+    }
+
+    pub fn write_port(port: u16, data: &[u8]) -> Result<Option<&[u8]>, SysCallError> {
         let req = SysCallRequest::SerialSend {
             port,
             src_buf: data.into(),
@@ -53,13 +341,92 @@ pub mod serial {
                     Ok(Some(&data[(datlen - remlen)..]))
                 } else {
                     // Unexpected!
-                    Err(())
+                    Err(SysCallError::Rejected)
                 }
             }
             SysCallSuccess::DataSent { remainder: None } => {
                 Ok(None)
             }
-            _ => Err(()),
+            _ => Err(SysCallError::Rejected),
+        }
+    }
+
+    /// Outgoing data for `port` that is held on to until it has been sent,
+    /// so a partial [write_port] doesn't have to be retried by hand.
+    ///
+    /// `B` is the storage, usually a [Pool](super::system::Pool).
+    /// [WriteBuffer::write] queues data and sends as much of it as it can
+    /// right away. Call [WriteBuffer::pump] later to retry the rest, until it
+    /// reports that everything was sent.
+    ///
+    /// When dropped, the buffer makes one last attempt to send what's left,
+    /// without waiting for room. Anything unsent after that is lost, so pump
+    /// until done first if every byte matters.
+    pub struct WriteBuffer<B: DerefMut<Target = [u8]>> {
+        port: u16,
+        buf: B,
+        // The unsent data is `buf[start..end]`
+        start: usize,
+        end: usize,
+    }
+
+    impl<B: DerefMut<Target = [u8]>> WriteBuffer<B> {
+        pub fn new(port: u16, buf: B) -> Self {
+            WriteBuffer { port, buf, start: 0, end: 0 }
+        }
+
+        /// The number of bytes queued, but not sent yet
+        pub fn pending(&self) -> usize {
+            self.end - self.start
+        }
+
+        /// Queue as much of `data` as fits, then try to send everything
+        /// queued. Returns how many bytes of `data` were queued; write the
+        /// rest again once some has been sent.
+        ///
+        /// If sending fails, the data stays queued, and [WriteBuffer::pump]
+        /// reports why. Once queued, data is never reported as failed here,
+        /// as the caller would write it again.
+        pub fn write(&mut self, data: &[u8]) -> Result<usize, SysCallError> {
+            // Make room by moving the unsent data to the front
+            if self.start != 0 {
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end -= self.start;
+                self.start = 0;
+            }
+
+            let amt = data.len().min(self.buf.len() - self.end);
+            self.buf[self.end..][..amt].copy_from_slice(&data[..amt]);
+            self.end += amt;
+
+            // Already queued, so a failed send is only reported by `pump()`
+            self.pump().ok();
+            Ok(amt)
+        }
+
+        /// Try to send whatever is still queued. Returns whether everything
+        /// has been sent.
+        pub fn pump(&mut self) -> Result<bool, SysCallError> {
+            if self.start != self.end {
+                let rem = write_port(self.port, &self.buf[self.start..self.end])?
+                    .map(|rem| rem.len())
+                    .unwrap_or(0);
+                self.start = self.end - rem;
+            }
+
+            if self.start == self.end {
+                self.start = 0;
+                self.end = 0;
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+    }
+
+    impl<B: DerefMut<Target = [u8]>> Drop for WriteBuffer<B> {
+        fn drop(&mut self) {
+            self.pump().ok();
         }
     }
 }
@@ -67,13 +434,415 @@ pub mod serial {
 pub mod time {
     use super::*;
 
-    pub fn sleep_micros(us: u32) -> Result<u32, ()> {
+    pub fn sleep_micros(us: u32) -> Result<u32, SysCallError> {
         let req = SysCallRequest::SleepMicros { us };
         let resp = try_syscall(req)?;
         if let SysCallSuccess::SleptMicros { us } = resp {
             Ok(us)
         } else {
-            Err(())
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    // The kernel can't tell a sleep of the whole tick counter range from no
+    // sleep at all, so longer sleeps are made in chunks of this size
+    const MAX_SLEEP_CHUNK_US: u32 = 1 << 31;
+
+    fn sleep_total_us(mut us: u64) -> Result<(), SysCallError> {
+        while us > 0 {
+            let chunk = us.min(MAX_SLEEP_CHUNK_US as u64) as u32;
+            sleep_micros(chunk)?;
+            us -= chunk as u64;
+        }
+        Ok(())
+    }
+
+    /// Sleep for (at least) `us` microseconds
+    pub fn sleep_us(us: u32) -> Result<(), SysCallError> {
+        sleep_total_us(us as u64)
+    }
+
+    /// Sleep for (at least) `ms` milliseconds. Sleeps longer than the
+    /// kernel can make in one go (about 35 minutes) are split up.
+    pub fn sleep_ms(ms: u32) -> Result<(), SysCallError> {
+        sleep_total_us((ms as u64) * 1000)
+    }
+
+    /// Sleep until the kernel's 1MHz tick counter reaches `deadline`.
+    ///
+    /// Returns the tick count on wakeup. Adding a fixed period to the
+    /// previous deadline (rather than to the returned tick) gives a periodic
+    /// loop that doesn't drift. The counter is 64 bits, so it doesn't wrap
+    /// in practice, and deadlines in the past return immediately.
+    pub fn sleep_until(deadline: u64) -> Result<u64, SysCallError> {
+        let req = SysCallRequest::SleepUntil { tick: deadline };
+        let resp = try_syscall(req)?;
+        if let SysCallSuccess::SleptUntil { tick } = resp {
+            Ok(tick)
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Sleep for up to `us` microseconds, while the kernel keeps processing
+    /// incoming serial data. Unlike [sleep_micros], this returns EARLY as
+    /// soon as data is available on `port`.
+    ///
+    /// Returns `(slept_us, available_bytes)`. If `available_bytes` is
+    /// non-zero, it can be read right away.
+    pub fn sleep_service(port: u16, us: u32) -> Result<(u32, usize), SysCallError> {
+        let req = SysCallRequest::SleepService { port, us };
+        if let SysCallSuccess::SleptService { us, available } = try_syscall(req)? {
+            Ok((us, available as usize))
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Measures elapsed time against the kernel's 1MHz tick counter
+    pub struct Stopwatch {
+        handle: u64,
+    }
+
+    impl Stopwatch {
+        pub fn start() -> Result<Self, SysCallError> {
+            if let SysCallSuccess::StopwatchStarted { handle } = try_syscall(SysCallRequest::StopwatchStart)? {
+                Ok(Stopwatch { handle })
+            } else {
+                Err(SysCallError::Rejected)
+            }
+        }
+
+        /// The ticks (microseconds) since the stopwatch was started. This
+        /// doesn't stop or reset it, so it can be called for each "lap".
+        pub fn lap(&self) -> Result<u64, SysCallError> {
+            let req = SysCallRequest::StopwatchLap { handle: self.handle };
+            if let SysCallSuccess::StopwatchLapped { elapsed_ticks } = try_syscall(req)? {
+                Ok(elapsed_ticks)
+            } else {
+                Err(SysCallError::Rejected)
+            }
+        }
+    }
+}
+
+pub mod system {
+    use super::*;
+    use core::ops::{Deref, DerefMut};
+
+    /// The version of the running kernel
+    pub struct KernelVersion {
+        pub major: u16,
+        pub minor: u16,
+        pub patch: u16,
+        /// Short git hash of the kernel build, or "unknown"
+        pub git_hash: &'static str,
+        /// Build time of the kernel, in seconds since the unix epoch
+        pub build_timestamp: u32,
+    }
+
+    /// Hardware identifiers, for inventory and provisioning
+    pub struct DeviceIds {
+        /// The nRF52840's factory programmed unique ID (FICR DEVICEID)
+        pub device_id: u64,
+        /// The QSPI flash's JEDEC ID, as `[manufacturer, memory type, capacity]`.
+        /// `None` if the kernel hasn't brought up the flash.
+        pub flash_jedec_id: Option<[u8; 3]>,
+    }
+
+    pub fn device_ids() -> Result<DeviceIds, SysCallError> {
+        let req = SysCallRequest::DeviceIds;
+        let resp = try_syscall(req)?;
+        if let SysCallSuccess::DeviceIds { device_id, flash_jedec_id } = resp {
+            Ok(DeviceIds { device_id, flash_jedec_id })
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Take over the status LED from the kernel's heartbeat (with
+    /// `StatusLed::On` or `Off`), or hand it back (`StatusLed::Heartbeat`).
+    /// The change shows up within about 100ms.
+    pub fn set_status_led(control: StatusLed) -> Result<(), SysCallError> {
+        let req = SysCallRequest::SetStatusLed { control };
+        if let SysCallSuccess::StatusLedSet = try_syscall(req)? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Make a board-specific `Vendor` request, handled by whatever the board
+    /// integrator registered for `id` in the kernel.
+    ///
+    /// `payload` is passed to the handler as-is, and the handler writes its
+    /// response (if any) into `out`. Returns the part of `out` it used.
+    pub fn vendor<'a>(id: u16, payload: &[u8], out: &'a mut [u8]) -> Result<&'a mut [u8], SysCallError> {
+        let req = SysCallRequest::Vendor {
+            id,
+            payload: payload.into(),
+            dest_buf: out.as_mut().into(),
+        };
+
+        if let SysCallSuccess::VendorHandled { dest_buf } = try_syscall(req)? {
+            let dblen = dest_buf.len as usize;
+            if dblen <= out.len() {
+                Ok(&mut out[..dblen])
+            } else {
+                Err(SysCallError::Rejected)
+            }
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// A working buffer, allocated for us from the kernel's heap.
+    ///
+    /// The kernel frees the memory when this is dropped, or when the app
+    /// exits. This is the only safe way to hold onto it: don't leak or
+    /// `mem::forget` a `Pool` and keep using the memory through a pointer,
+    /// and don't hold on to it longer than needed, as the heap is shared
+    /// with the kernel's own buffers (like received serial data). The kernel
+    /// only hands out a small number of pools at a time.
+    ///
+    /// Unlike other memory outside of app RAM, a pool can be used as the
+    /// buffer for other syscalls, like [serial::read_port](super::serial::read_port).
+    pub struct Pool {
+        data: &'static mut [u8],
+    }
+
+    impl Pool {
+        /// Allocate a zeroed pool of `bytes` bytes
+        pub fn alloc(bytes: u32) -> Result<Self, SysCallError> {
+            let req = SysCallRequest::AllocPool { bytes };
+            if let SysCallSuccess::PoolAllocated { buf } = try_syscall(req)? {
+                // The kernel keeps this allocation alive until we free it
+                let data = unsafe { buf.to_slice_mut() };
+                Ok(Pool { data })
+            } else {
+                Err(SysCallError::Rejected)
+            }
+        }
+    }
+
+    impl Deref for Pool {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            self.data
+        }
+    }
+
+    impl DerefMut for Pool {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            self.data
+        }
+    }
+
+    impl Drop for Pool {
+        fn drop(&mut self) {
+            let req = SysCallRequest::FreePool { buf: (&*self.data).into() };
+            // If this fails, the kernel keeps the buffer until the app exits.
+            try_syscall(req).ok();
+        }
+    }
+
+    /// Put the CPU into a low-power (System ON) sleep, until `wake_on`.
+    /// See [WakeSource] for the supported wake sources.
+    pub fn low_power(wake_on: WakeSource) -> Result<(), SysCallError> {
+        let req = SysCallRequest::LowPower { wake_on };
+        if let SysCallSuccess::Woke = try_syscall(req)? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// What caused the last reset, and how the application before it
+    /// exited, if it did. See [ResetCause].
+    pub fn reset_reason() -> Result<(ResetCause, Option<ExitReason>), SysCallError> {
+        if let SysCallSuccess::ResetReason { cause, exit } = try_syscall(SysCallRequest::ResetReason)? {
+            Ok((cause, exit))
+        } else {
+            Err(SysCallError::Rejected)
         }
     }
+
+    /// Set how much the kernel logs. See [LogLevel].
+    pub fn set_log_level(level: LogLevel) -> Result<(), SysCallError> {
+        if let SysCallSuccess::LogLevelSet = try_syscall(SysCallRequest::SetLogLevel { level })? {
+            Ok(())
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+
+    /// Exit the application. See [ExitReason] for what the kernel does next.
+    pub fn exit(reason: ExitReason) -> ! {
+        // The kernel doesn't return from this syscall, unless it couldn't
+        // be made at all (e.g. the bridge was busy). Keep trying.
+        loop {
+            let req = SysCallRequest::Exit { reason };
+            try_syscall(req).ok();
+        }
+    }
+
+    /// Reboot into the kernel's built-in default image, e.g. to recover
+    /// from bad flash contents. Exits this application with
+    /// [ExitReason::Reboot].
+    pub fn boot_default() -> ! {
+        // As with `exit()`, only returns if the syscall couldn't be made
+        loop {
+            try_syscall(SysCallRequest::BootDefault).ok();
+        }
+    }
+
+    pub fn kernel_version() -> Result<KernelVersion, SysCallError> {
+        let req = SysCallRequest::KernelVersion;
+        let resp = try_syscall(req)?;
+        if let SysCallSuccess::VersionInfo { major, minor, patch, git_hash, build_timestamp } = resp {
+            // The hash lives in the kernel's flash, and is valid forever.
+            let git_hash = unsafe { git_hash.to_slice() };
+            let git_hash = core::str::from_utf8(git_hash).map_err(|_| SysCallError::Rejected)?;
+            Ok(KernelVersion { major, minor, patch, git_hash, build_timestamp })
+        } else {
+            Err(SysCallError::Rejected)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_shim;
+    use std::{cell::RefCell, rc::Rc, vec::Vec};
+
+    // Fake a kernel that records every sleep
+    fn record_sleeps() -> Rc<RefCell<Vec<u32>>> {
+        let sleeps = Rc::new(RefCell::new(Vec::new()));
+        let log = sleeps.clone();
+        test_shim::set_handler(move |req| match req {
+            SysCallRequest::SleepMicros { us } => {
+                log.borrow_mut().push(us);
+                Ok(SysCallSuccess::SleptMicros { us })
+            }
+            _ => Err(SysCallError::Unknown),
+        });
+        sleeps
+    }
+
+    #[test]
+    fn short_sleep_is_one_syscall() {
+        let sleeps = record_sleeps();
+        time::sleep_us(1234).unwrap();
+        time::sleep_ms(5).unwrap();
+        assert_eq!(*sleeps.borrow(), [1234, 5000]);
+    }
+
+    #[test]
+    fn zero_sleep_makes_no_syscall() {
+        let sleeps = record_sleeps();
+        time::sleep_ms(0).unwrap();
+        assert!(sleeps.borrow().is_empty());
+    }
+
+    #[test]
+    fn long_sleep_is_split() {
+        let sleeps = record_sleeps();
+        time::sleep_ms(u32::MAX).unwrap();
+
+        let sleeps = sleeps.borrow();
+        let total: u64 = sleeps.iter().map(|us| *us as u64).sum();
+        assert_eq!(total, (u32::MAX as u64) * 1000);
+        assert!(sleeps.iter().all(|us| *us <= (1 << 31)));
+    }
+
+    #[test]
+    fn failed_syscall_is_reported() {
+        test_shim::set_handler(|_| Err(SysCallError::HeapFull));
+        assert_eq!(time::sleep_ms(1), Err(SysCallError::HeapFull));
+    }
+
+    #[test]
+    fn wait_data_timeout_is_not_an_error() {
+        test_shim::set_handler(|req| match req {
+            SysCallRequest::SerialWaitData { port: 1, timeout_ticks: Some(_) } => Err(SysCallError::Timeout),
+            SysCallRequest::SerialWaitData { port: 2, timeout_ticks: Some(_) } => Ok(SysCallSuccess::DataAvailable { bytes: 3 }),
+            _ => Err(SysCallError::NoSuchPort),
+        });
+        assert_eq!(serial::wait_data_timeout(1, 1000), Ok(None));
+        assert_eq!(serial::wait_data_timeout(2, 1000), Ok(Some(3)));
+        assert_eq!(serial::wait_data_timeout(3, 1000), Err(SysCallError::NoSuchPort));
+    }
+
+    // Fake a kernel that accepts up to `room` bytes per send on port 1,
+    // and records the size of every send
+    fn record_sends(room: Rc<RefCell<usize>>) -> Rc<RefCell<Vec<usize>>> {
+        static UNSENT: [u8; 64] = [0; 64];
+        let sends = Rc::new(RefCell::new(Vec::new()));
+        let log = sends.clone();
+        test_shim::set_handler(move |req| match req {
+            SysCallRequest::SerialSend { port: 1, src_buf } => {
+                let len = src_buf.len as usize;
+                let amt = len.min(*room.borrow());
+                log.borrow_mut().push(amt);
+                let remainder = (amt < len).then(|| (&UNSENT[..(len - amt)]).into());
+                Ok(SysCallSuccess::DataSent { remainder })
+            }
+            _ => Err(SysCallError::NoSuchPort),
+        });
+        sends
+    }
+
+    #[test]
+    fn write_buffer_retries_partial_sends() {
+        let room = Rc::new(RefCell::new(3));
+        let sends = record_sends(room.clone());
+
+        let mut wb = serial::WriteBuffer::new(1, vec![0u8; 8]);
+        assert_eq!(wb.write(b"hello"), Ok(5));
+        assert_eq!(wb.pending(), 2);
+
+        // Only the first 3 bytes of the remainder (2 old, 1 new) fit
+        assert_eq!(wb.write(b"world!"), Ok(6));
+        assert_eq!(wb.pending(), 5);
+
+        *room.borrow_mut() = 0;
+        assert_eq!(wb.pump(), Ok(false));
+        *room.borrow_mut() = 64;
+        assert_eq!(wb.pump(), Ok(true));
+        assert_eq!(wb.pending(), 0);
+
+        // Nothing left, so neither pumping nor dropping sends anything
+        assert_eq!(wb.pump(), Ok(true));
+        drop(wb);
+        assert_eq!(*sends.borrow(), [3, 3, 0, 5]);
+    }
+
+    #[test]
+    fn write_buffer_takes_what_fits() {
+        let room = Rc::new(RefCell::new(0));
+        let sends = record_sends(room.clone());
+
+        let mut wb = serial::WriteBuffer::new(1, vec![0u8; 4]);
+        assert_eq!(wb.write(b"abcdef"), Ok(4));
+        assert_eq!(wb.write(b"ef"), Ok(0));
+
+        // The last attempt happens on drop
+        *room.borrow_mut() = 64;
+        drop(wb);
+        assert_eq!(*sends.borrow(), [0, 0, 4]);
+    }
+
+    #[test]
+    fn write_buffer_send_failure_keeps_data() {
+        let room = Rc::new(RefCell::new(64));
+        record_sends(room);
+
+        let mut wb = serial::WriteBuffer::new(2, vec![0u8; 4]);
+        assert_eq!(wb.write(b"ab"), Ok(2));
+        assert_eq!(wb.pending(), 2);
+        assert_eq!(wb.pump(), Err(SysCallError::NoSuchPort));
+        assert_eq!(wb.pending(), 2);
+    }
 }
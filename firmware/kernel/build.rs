@@ -0,0 +1,80 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run git with `args`, returning its trimmed output if it succeeded
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|s| s.trim().to_string())
+}
+
+fn main() {
+    // Stash the short git hash of the kernel build, so userspace (and the
+    // host) can figure out what it is talking to.
+    let hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=KERNEL_GIT_HASH={}", hash);
+
+    // Re-run when HEAD moves. `HEAD` itself only changes on a checkout: a
+    // commit updates the branch's ref file instead, or `packed-refs`, if
+    // the ref has been packed. Only watch the ones that exist, as cargo
+    // re-runs on every build for a missing file.
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        let git_dir = std::path::Path::new(&git_dir);
+        let mut watch = vec![git_dir.join("HEAD"), git_dir.join("packed-refs")];
+
+        // A detached HEAD has no ref file
+        if let Some(head_ref) = git(&["rev-parse", "--symbolic-full-name", "HEAD"]) {
+            if head_ref.starts_with("refs/") {
+                watch.push(git_dir.join(head_ref));
+            }
+        }
+
+        for path in watch.iter().filter(|path| path.exists()) {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+
+    // Respect SOURCE_DATE_EPOCH for reproducible builds, otherwise use the
+    // current time. Note: this is only refreshed when the build script
+    // re-runs: on a commit or checkout, or when one of the inputs below
+    // changes. Rebuilding for source changes alone keeps the old timestamp.
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=KERNEL_BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // The application image baked into the kernel, and booted on every
+    // reset. Override it with `PELLEGRINO_DEFAULT_IMAGE=path/to/app.bin`
+    // (relative paths are relative to this crate).
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let image = std::env::var("PELLEGRINO_DEFAULT_IMAGE")
+        .unwrap_or_else(|_| "appbins/test.bin".to_string());
+    let image = std::path::Path::new(&manifest_dir).join(image);
+    println!("cargo:rerun-if-env-changed=PELLEGRINO_DEFAULT_IMAGE");
+
+    // Otherwise the build fails later, with a far less helpful error from
+    // `include_bytes!`
+    if !image.exists() {
+        panic!(
+            "Default application image {} not found. Build one (e.g. copy an \
+            image from appbins/), or set PELLEGRINO_DEFAULT_IMAGE to its path.",
+            image.display()
+        );
+    }
+
+    println!("cargo:rustc-env=KERNEL_DEFAULT_IMAGE={}", image.display());
+    println!("cargo:rerun-if-changed={}", image.display());
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -46,6 +46,10 @@ SECTIONS
   } > APP
 }
 
+/* The application RAM, used by the kernel to check syscall slices */
+_app_start = ORIGIN(APP);
+_app_len = LENGTH(APP);
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* You may want to use this variable to locate the call stack and static
@@ -1,8 +1,39 @@
-/// Allocation types for the Anachro PC.
-///
-/// NOTE: This module makes STRONG assumptions that the allocator will be a singleton.
-/// This is currently fine, but it is not allowed to make multiple instances of the
-/// types within.
+//! Allocation types for the Anachro PC.
+//!
+//! NOTE: This module makes STRONG assumptions that the allocator will be a singleton.
+//! This is currently fine, but it is not allowed to make multiple instances of the
+//! types within.
+//!
+//! ## Locking
+//!
+//! The heap is guarded by a simple try-lock (`AHeap::try_lock()`), which
+//! never waits. Rather than making the lock smarter, the kernel avoids
+//! contention altogether by only ALLOCATING from one context:
+//!
+//! * `init`, before any interrupts are enabled, and
+//! * the `SVCall` handler (priority 1), which services all syscalls,
+//!   including the serial `process()` loop that queues incoming messages.
+//!
+//! The USB interrupt (priority 2) only moves bytes between the USB
+//! peripheral and the bbqueues, and never touches the heap. Since tasks at
+//! the same priority can't preempt each other, `try_lock()` can't fail
+//! because someone else holds the lock. If it does fail, something is
+//! allocating from the wrong context, which is a bug.
+//!
+//! FREEING is allowed from any context. If the heap is locked, the
+//! allocation is pushed onto the lock-free free queue instead, and reclaimed
+//! by the next allocation.
+//!
+//! Worst case behavior:
+//!
+//! * An allocation takes the lock for one first-fit search of the free
+//!   list, plus reclaiming up to `FREE_Q_LEN` deferred frees. It never
+//!   waits for anything.
+//! * A free takes the lock for one free list insertion, or (if the heap is
+//!   locked) one queue push. If more than `FREE_Q_LEN` frees are deferred
+//!   before the next allocation, the kernel panics.
+//! * If the heap is simply out of space, allocations fail, and incoming
+//!   serial messages are dropped (and counted as overruns).
 
 use core::{
     alloc::Layout,
@@ -220,6 +251,8 @@ impl<T> Drop for HeapBox<T> {
 pub struct HeapArray<T> {
     count: usize,
     ptr: *mut T,
+    // Alignment of the allocation, at least `align_of::<T>()`
+    align: usize,
 }
 
 unsafe impl<T> Send for HeapArray<T> { }
@@ -251,7 +284,7 @@ impl<T> HeapArray<T> {
         // offers a checked method.
         let layout = {
             let array_size = size_of::<T>() * self.count;
-            Layout::from_size_align_unchecked(array_size, align_of::<T>())
+            Layout::from_size_align_unchecked(array_size, self.align)
         };
         FreeBox {
             ptr: NonNull::new_unchecked(self.ptr.cast::<u8>()),
@@ -383,7 +416,65 @@ impl HeapGuard {
             }
         }
 
-        Ok(HeapArray { ptr, count })
+        Ok(HeapArray { ptr, count, align: align_of::<T>() })
+    }
+
+    /// Attempt to allocate a HeapArray holding a copy of `data`
+    ///
+    /// Unlike `alloc_box_array()` followed by a copy, the allocation is
+    /// initialized exactly once. If space was available, the allocation
+    /// will be returned. If not, an error will be returned
+    ///
+    /// Like the other `HeapGuard` allocation methods, the error is `()`:
+    /// callers can't do anything different for a bad layout (which needs a
+    /// slice of over `isize::MAX` bytes) than for a full heap.
+    //
+    // TODO: The savings over `alloc_box_array()` plus a copy haven't been
+    // measured on target yet, e.g. with the stopwatch syscalls.
+    pub fn alloc_box_from_slice<T: Copy>(&mut self, data: &[T]) -> Result<HeapArray<T>, ()> {
+        // Clean up any pending allocs
+        self.clean_allocs();
+
+        // See `alloc_box_array()` for why this could fail
+        let layout = Layout::array::<T>(data.len()).map_err(drop)?;
+
+        // Then, attempt to allocate the requested T.
+        let nnu8 = self.deref_mut().allocate_first_fit(layout)?;
+        let ptr = nnu8.as_ptr().cast::<T>();
+
+        // And initialize it with the contents given to us
+        unsafe {
+            ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+        }
+
+        Ok(HeapArray { ptr, count: data.len(), align: align_of::<T>() })
+    }
+
+    /// Attempt to allocate a zeroed HeapArray of `count` bytes, aligned to
+    /// at least `align` bytes, for use as a DMA buffer.
+    ///
+    /// The alignment is always at least 4 bytes (one word), and must be a
+    /// power of two. The heap storage lives in RAM, so unlike an arbitrary
+    /// `&[u8]` (which may point into flash), the buffer can always be handed
+    /// to EasyDMA. If space was available, the allocation will be returned.
+    /// If not, an error will be returned
+    pub fn alloc_box_aligned(&mut self, count: usize, align: usize) -> Result<HeapArray<u8>, ()> {
+        // Clean up any pending allocs
+        self.clean_allocs();
+
+        // This fails if `align` isn't a power of two
+        let align = align.max(4);
+        let layout = Layout::from_size_align(count, align).map_err(drop)?;
+
+        // Then, attempt to allocate the requested bytes.
+        let nnu8 = self.deref_mut().allocate_first_fit(layout)?;
+        let ptr = nnu8.as_ptr();
+
+        unsafe {
+            ptr.write_bytes(0, count);
+        }
+
+        Ok(HeapArray { ptr, count, align })
     }
 }
 
@@ -0,0 +1,67 @@
+//! A kernel-managed heartbeat on `led1`
+//!
+//! The kernel pulses the LED once a second from a timer task, regardless of
+//! what the application is doing. If the LED stops pulsing (stays solid on,
+//! or off), the kernel's interrupts are wedged, e.g. stuck in a busy-wait.
+//!
+//! An application can take over the LED with the `SetStatusLed` syscall,
+//! and hand it back by setting it to `StatusLed::Heartbeat`. It is handed
+//! back automatically when the application exits.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use common::StatusLed;
+use embedded_hal::digital::v2::OutputPin;
+use nrf52840_hal::gpio::{p1::P1_15, Disconnected, Level, Output, Pin, PushPull};
+
+/// How often `Heartbeat::tick()` must be called
+pub const HEARTBEAT_TICK_MS: u32 = 100;
+
+/// Ticks per heartbeat. The LED is lit for the first tick of each.
+const TICKS_PER_BEAT: u8 = 10;
+
+/// Who controls the LED, as a `StatusLed` discriminant
+static CONTROL: AtomicU8 = AtomicU8::new(StatusLed::Heartbeat as u8);
+
+/// Set who controls the LED. This takes effect on the next tick.
+pub fn set_control(control: StatusLed) {
+    CONTROL.store(control as u8, Ordering::SeqCst);
+}
+
+fn control() -> StatusLed {
+    match CONTROL.load(Ordering::SeqCst) {
+        x if x == StatusLed::Off as u8 => StatusLed::Off,
+        x if x == StatusLed::On as u8 => StatusLed::On,
+        _ => StatusLed::Heartbeat,
+    }
+}
+
+pub struct Heartbeat {
+    led: Pin<Output<PushPull>>,
+    tick: u8,
+}
+
+impl Heartbeat {
+    pub fn new(led: P1_15<Disconnected>) -> Self {
+        Self {
+            led: led.into_push_pull_output(Level::Low).degrade(),
+            tick: 0,
+        }
+    }
+
+    /// Update the LED. Call this every `HEARTBEAT_TICK_MS`.
+    pub fn tick(&mut self) {
+        let lit = match control() {
+            StatusLed::Heartbeat => self.tick == 0,
+            StatusLed::On => true,
+            StatusLed::Off => false,
+        };
+        self.tick = (self.tick + 1) % TICKS_PER_BEAT;
+
+        // The LED is active high. Setting a GPIO can't fail.
+        if lit {
+            self.led.set_high().ok();
+        } else {
+            self.led.set_low().ok();
+        }
+    }
+}
@@ -1,19 +1,71 @@
 //! A USB-Serial driver for the nRF52840
 
-use core::ops::Deref;
+use core::{ops::Deref, ptr::null_mut, sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, Ordering}};
 
 use bbqueue::{BBBuffer, Consumer, Producer};
 use nrf52840_hal::{usbd::{Usbd, UsbPeripheral}, pac::USBD};
-use sportty::{Message, max_encoding_length};
-use usb_device::{device::UsbDevice, UsbError};
+use sportty::{Message, max_encoding_length, max_data_length, MAX_PORT, stream::{StreamDecoder, Status}};
+use usb_device::{class::UsbClass, device::{UsbDevice, UsbDeviceState}, UsbError};
 use usbd_serial::SerialPort;
-use heapless::{LinearMap, Deque};
+use heapless::{LinearMap, Deque, Vec};
 use crate::alloc::{HeapArray, HEAP};
+use crate::traits::SerialError;
+use common::{PortMode, PortPriority, PortInfo, PortStats, LinkState, LinkStats, LineCoding, Parity, StopBits};
+use groundhog_nrf52::GlobalRollingTimer;
+use crate::monotonic::now64;
+use groundhog::RollingTimer;
 
 const USB_BUF_SZ: usize = 4096;
 static UART_INC: BBBuffer<USB_BUF_SZ> = BBBuffer::new();
 static UART_OUT: BBBuffer<USB_BUF_SZ> = BBBuffer::new();
 
+/// Outgoing frames for `PortPriority::High` ports, which the ISR sends
+/// ahead of anything in `UART_OUT`.
+const USB_HI_BUF_SZ: usize = 1024;
+static UART_OUT_HI: BBBuffer<USB_HI_BUF_SZ> = BBBuffer::new();
+
+/// The maximum number of dedicated CDC-ACM interfaces, in addition to the
+/// multiplexed one. Each CDC-ACM interface uses two IN endpoints, and the
+/// nRF52840 has seven (plus the control endpoint).
+pub const MAX_DEDICATED: usize = 2;
+const DEDICATED_BUF_SZ: usize = 1024;
+static DEDICATED_INC: [BBBuffer<DEDICATED_BUF_SZ>; MAX_DEDICATED] = [BBBuffer::new(), BBBuffer::new()];
+static DEDICATED_OUT: [BBBuffer<DEDICATED_BUF_SZ>; MAX_DEDICATED] = [BBBuffer::new(), BBBuffer::new()];
+
+/// Set by the ISR when the host resets the USB link, cleared by the
+/// "userspace" side once it has flushed any stale incoming data.
+static USB_RESET: AtomicBool = AtomicBool::new(false);
+
+/// Set by the "userspace" side when a port switches to `PortMode::Raw`,
+/// cleared by the ISR once it has discarded the queued outgoing frames.
+/// Raw data has no frame boundaries, so none of those frames could be sent
+/// without splicing them into (or in front of part of) the raw stream.
+static OUT_FLUSH: AtomicBool = AtomicBool::new(false);
+
+/// The link state seen by the ISR on its last poll, as a `LinkState`
+/// discriminant, and the number of times the host suspended the bus.
+static USB_LINK_STATE: AtomicU8 = AtomicU8::new(LinkState::Detached as u8);
+static USB_SUSPENDS: AtomicU32 = AtomicU32::new(0);
+
+/// The line coding of each CDC-ACM interface (the multiplexed one, then
+/// each dedicated one), as last seen by the ISR
+static LINE_CODINGS: [LineCodingCell; MAX_DEDICATED + 1] = [
+    LineCodingCell::new(),
+    LineCodingCell::new(),
+    LineCodingCell::new(),
+];
+
+/// The ISR half of the driver, registered on every poll, so that a panic
+/// handler can still reach the host. See [panic_report].
+static PANIC_ISR: AtomicPtr<UsbUartIsr> = AtomicPtr::new(null_mut());
+
+/// How long [panic_report] keeps trying to get its message out
+const PANIC_TIMEOUT_MS: u32 = 100;
+
+/// Set by the ISR whenever it commits new incoming bytes, cleared by the
+/// "userspace" side before it checks for data in `wait_data`.
+static USB_DATA: AtomicBool = AtomicBool::new(false);
+
 /// A type alias for the nRF52840 USB Peripheral type
 pub type AUsbPeripheral = Usbd<UsbPeripheral<'static>>;
 
@@ -28,62 +80,406 @@ pub struct UsbUartIsr {
     dev: AUsbDevice,
     ser: ASerialPort,
     out: Consumer<'static, USB_BUF_SZ>,
+    out_hi: Consumer<'static, USB_HI_BUF_SZ>,
     inc: Producer<'static, USB_BUF_SZ>,
+    last_state: UsbDeviceState,
+    dedicated: Vec<DedicatedIsr, MAX_DEDICATED>,
+
+    // The queue we are part way through sending a frame from, if any. We
+    // can only switch queues between frames.
+    mid_frame: Option<PortPriority>,
+}
+
+/// A CDC-ACM interface which carries a single logical port, used with
+/// [setup_usb_uart_dedicated].
+///
+/// Data on a dedicated interface is a plain byte stream, without sportty
+/// framing, so it appears to the host as a separate, ordinary serial device
+/// (e.g. another `/dev/ttyACMx`).
+pub struct DedicatedPort {
+    pub port: u16,
+    pub ser: ASerialPort,
+}
+
+struct DedicatedIsr {
+    ser: ASerialPort,
+    out: Consumer<'static, DEDICATED_BUF_SZ>,
+    inc: Producer<'static, DEDICATED_BUF_SZ>,
+}
+
+struct DedicatedSys {
+    port: u16,
+    out: Producer<'static, DEDICATED_BUF_SZ>,
+    inc: Consumer<'static, DEDICATED_BUF_SZ>,
 }
 
 impl UsbUartIsr {
     /// Service the USB ISR, which is triggered by either a regular polling timer,
     /// or some kind of USB interrupt.
     pub fn poll(&mut self) {
+        // The ISR half lives in a static once the interrupt is running, so
+        // this address stays valid for the panic handler.
+        PANIC_ISR.store(self, Ordering::Relaxed);
+
         // Service the relevant hardware logic
-        self.dev.poll(&mut [&mut self.ser]);
-
-        // If there is data to be sent...
-        if let Ok(rgr) = self.out.read() {
-            match self.ser.write(&rgr) {
-                // ... and there is room to send it, then send it.
-                Ok(sz) if sz > 0 => {
-                    rgr.release(sz);
-                },
-                // ... and there is no room to send it, then just bail.
-                Ok(_) | Err(UsbError::WouldBlock) => {
-                    // Just silently drop the read grant
-                }
-                // ... and there is a USB error, then panic.
-                Err(_) => defmt::panic!("Usb Error Write!"),
+        {
+            let mut classes: Vec<&mut dyn UsbClass<AUsbPeripheral>, { MAX_DEDICATED + 1 }> = Vec::new();
+            classes.push(&mut self.ser).ok();
+            for ded in self.dedicated.iter_mut() {
+                classes.push(&mut ded.ser).ok();
             }
+            self.dev.poll(&mut classes);
         }
 
-        // If there is room to receive data...
-        if let Ok(mut wgr) = self.inc.grant_max_remaining(128) {
-            match self.ser.read(&mut wgr) {
-                // ... and there is data to be read, then take it.
-                Ok(sz) if sz > 0 => {
-                    wgr.commit(sz);
-                },
-                // ... and there is no data to be read, then just bail.
-                Ok(_) | Err(UsbError::WouldBlock) => {
-                    // Just silently drop the write grant
-                }
-                // ... and there is a USB error, then panic.
-                Err(_) => defmt::panic!("Usb Error Read!"),
+        // A bus reset puts the device back into the `Default` state. When
+        // that happens, anything still in flight belongs to the old session,
+        // and may be a partial frame. Drop it, so both ends resync cleanly.
+        let state = self.dev.state();
+        if state == UsbDeviceState::Default && self.last_state != UsbDeviceState::Default {
+            self.flush_out();
+            USB_RESET.store(true, Ordering::SeqCst);
+        }
+        if state == UsbDeviceState::Suspend && self.last_state != UsbDeviceState::Suspend {
+            USB_SUSPENDS.fetch_add(1, Ordering::SeqCst);
+        }
+        let link = match state {
+            UsbDeviceState::Configured => LinkState::Configured,
+            UsbDeviceState::Suspend => LinkState::Suspended,
+            _ => LinkState::Detached,
+        };
+        USB_LINK_STATE.store(link as u8, Ordering::SeqCst);
+        self.last_state = state;
+
+        LINE_CODINGS[0].update(&self.ser);
+        for (i, ded) in self.dedicated.iter().enumerate() {
+            LINE_CODINGS[i + 1].update(&ded.ser);
+        }
+
+        if OUT_FLUSH.swap(false, Ordering::SeqCst) {
+            drain(&mut self.out);
+            drain(&mut self.out_hi);
+            self.mid_frame = None;
+        }
+
+        self.write_prioritized();
+        read_in(&mut self.ser, &mut self.inc);
+        for ded in self.dedicated.iter_mut() {
+            service_serial(&mut ded.ser, &mut ded.out, &mut ded.inc);
+        }
+    }
+
+    /// Send outgoing frames, strictly preferring high priority frames.
+    ///
+    /// Sportty frames are COBS encoded, so a zero byte always (and only)
+    /// ends a frame. This lets us tell when we are between frames, without
+    /// knowing anything else about the frames in the queues.
+    ///
+    /// Data sent by a `PortMode::Raw` port isn't framed, and may contain
+    /// zero bytes anywhere, so this only works because the queues are
+    /// flushed when a port switches to raw mode (see `OUT_FLUSH`), and no
+    /// other port can send while it is in raw mode. Frames are only ever
+    /// committed to the queues whole, so a queue we have emptied is always
+    /// between frames.
+    fn write_prioritized(&mut self) {
+        let prio = match self.mid_frame {
+            Some(prio) => prio,
+            None if self.out_hi.read().is_ok() => PortPriority::High,
+            None => PortPriority::Normal,
+        };
+
+        let last = match prio {
+            PortPriority::High => write_out(&mut self.ser, &mut self.out_hi),
+            PortPriority::Normal => write_out(&mut self.ser, &mut self.out),
+        };
+
+        if let Some(last) = last {
+            let drained = match prio {
+                PortPriority::High => self.out_hi.read().is_err(),
+                PortPriority::Normal => self.out.read().is_err(),
+            };
+            self.mid_frame = if (last == 0) || drained { None } else { Some(prio) };
+        }
+    }
+
+    /// Discard all pending outgoing data
+    fn flush_out(&mut self) {
+        drain(&mut self.out);
+        drain(&mut self.out_hi);
+        self.mid_frame = None;
+        for ded in self.dedicated.iter_mut() {
+            drain(&mut ded.out);
+        }
+    }
+}
+
+/// A line coding shared between the ISR (which writes it) and the
+/// "userspace" half (which reads it)
+struct LineCodingCell {
+    baud: AtomicU32,
+    // `LINE_CODING_SET`, data bits, parity, and stop bits, one byte each,
+    // as `usbd_serial`'s raw values
+    format: AtomicU32,
+}
+
+/// Set in `LineCodingCell::format` once the host has set a line coding
+const LINE_CODING_SET: u32 = 1 << 24;
+
+impl LineCodingCell {
+    const fn new() -> Self {
+        Self {
+            baud: AtomicU32::new(0),
+            format: AtomicU32::new(0),
+        }
+    }
+
+    /// Record the line coding of `ser`. Only called from the ISR.
+    fn update(&self, ser: &ASerialPort) {
+        let lc = ser.line_coding();
+
+        // `usbd_serial` starts out with 8000 baud, 8N1. Until that changes,
+        // the host hasn't set anything.
+        let untouched = (lc.data_rate() == 8_000)
+            && (lc.data_bits() == 8)
+            && (lc.parity_type() == usbd_serial::ParityType::None)
+            && (lc.stop_bits() == usbd_serial::StopBits::One);
+        if untouched && (self.format.load(Ordering::SeqCst) & LINE_CODING_SET) == 0 {
+            return;
+        }
+
+        let format = LINE_CODING_SET
+            | (lc.data_bits() as u32)
+            | ((lc.parity_type() as u32) << 8)
+            | ((lc.stop_bits() as u32) << 16);
+        self.baud.store(lc.data_rate(), Ordering::SeqCst);
+        self.format.store(format, Ordering::SeqCst);
+    }
+
+    fn get(&self) -> LineCoding {
+        // The ISR can't run in between, so both halves are from one update
+        let (baud, format) = cortex_m::interrupt::free(|_| {
+            (self.baud.load(Ordering::SeqCst), self.format.load(Ordering::SeqCst))
+        });
+
+        if (format & LINE_CODING_SET) == 0 {
+            return LineCoding::default();
+        }
+
+        LineCoding {
+            baud,
+            data_bits: format as u8,
+            parity: match (format >> 8) as u8 {
+                1 => Parity::Odd,
+                2 => Parity::Even,
+                3 => Parity::Mark,
+                4 => Parity::Space,
+                _ => Parity::None,
+            },
+            stop_bits: match (format >> 16) as u8 {
+                1 => StopBits::OnePointFive,
+                2 => StopBits::Two,
+                _ => StopBits::One,
+            },
+        }
+    }
+
+    fn reset(&self) {
+        self.format.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Best-effort attempt to send `msg` to the host as a port 0 message, so a
+/// user without a debug probe can see why the device died.
+///
+/// This drives the USB peripheral directly, bypassing the queues, and gives
+/// up after [PANIC_TIMEOUT_MS]. Nothing is sent if the USB interrupt has
+/// never run, or if the timer isn't running.
+///
+/// # Safety
+///
+/// This must only be called from a panic handler, with interrupts disabled,
+/// and normal operation must never resume afterwards. The ISR half may be
+/// aliased (e.g. if we panicked inside the USB interrupt), which is only
+/// acceptable because we are going down anyway.
+pub unsafe fn panic_report(msg: &[u8]) {
+    let isr = match PANIC_ISR.load(Ordering::Relaxed).as_mut() {
+        Some(isr) => isr,
+        None => return,
+    };
+
+    let timer = GlobalRollingTimer::default();
+    if !timer.is_initialized() {
+        return;
+    }
+
+    // Start with a zero, to terminate any partial frame the host has already
+    // received, so it resyncs on our message.
+    let mut frame = [0u8; 320];
+    let msg = &msg[..msg.len().min(256)];
+    let used = match Message::fragment(0, msg, false).encode_to(&mut frame[1..]) {
+        Ok(enc) => enc.len(),
+        Err(_) => return,
+    };
+    let mut window = &frame[..(used + 1)];
+
+    let start = timer.get_ticks();
+    while !window.is_empty() && timer.millis_since(start) < PANIC_TIMEOUT_MS {
+        isr.dev.poll(&mut [&mut isr.ser]);
+        if let Ok(sz) = isr.ser.write(window) {
+            window = &window[sz..];
+        }
+    }
+
+    // Give the last packet a chance to actually leave
+    while timer.millis_since(start) < PANIC_TIMEOUT_MS {
+        isr.dev.poll(&mut [&mut isr.ser]);
+        if isr.ser.flush().is_ok() {
+            break;
+        }
+    }
+}
+
+/// Move data between a CDC-ACM serial port and its pair of queues
+fn service_serial<const N: usize>(
+    ser: &mut ASerialPort,
+    out: &mut Consumer<'static, N>,
+    inc: &mut Producer<'static, N>,
+) {
+    write_out(ser, out);
+    read_in(ser, inc);
+}
+
+/// Send what we can from `out`. Returns the last byte sent, if any were.
+fn write_out<const N: usize>(ser: &mut ASerialPort, out: &mut Consumer<'static, N>) -> Option<u8> {
+    // If there is data to be sent...
+    if let Ok(rgr) = out.read() {
+        match ser.write(&rgr) {
+            // ... and there is room to send it, then send it.
+            Ok(sz) if sz > 0 => {
+                let last = rgr[sz - 1];
+                rgr.release(sz);
+                return Some(last);
+            },
+            // ... and there is no room to send it, then just bail.
+            Ok(_) | Err(UsbError::WouldBlock) => {
+                // Just silently drop the read grant
             }
+            // ... and there is a USB error, then panic.
+            Err(_) => defmt::panic!("Usb Error Write!"),
         }
     }
+
+    None
+}
+
+/// Receive what we can into `inc`
+///
+/// NOTE: This never drops data. If `inc` is full (or only has a little room),
+/// whatever we don't read stays in the serial port's buffer and the USB
+/// endpoint, and the host is NAK'd until we make room. Data is only lost
+/// further up, if a port's queue is full (see `LinkStats::overruns`).
+fn read_in<const N: usize>(ser: &mut ASerialPort, inc: &mut Producer<'static, N>) {
+    // If there is room to receive data...
+    if let Ok(mut wgr) = inc.grant_max_remaining(128) {
+        match ser.read(&mut wgr) {
+            // ... and there is data to be read, then take it.
+            Ok(sz) if sz > 0 => {
+                wgr.commit(sz);
+                USB_DATA.store(true, Ordering::SeqCst);
+            },
+            // ... and there is no data to be read, then just bail.
+            Ok(_) | Err(UsbError::WouldBlock) => {
+                // Just silently drop the write grant
+            }
+            // ... and there is a USB error, then panic.
+            Err(_) => defmt::panic!("Usb Error Read!"),
+        }
+    }
+}
+
+/// Discard everything currently readable from a queue
+fn drain<const N: usize>(cons: &mut Consumer<'static, N>) {
+    while let Ok(rgr) = cons.read() {
+        let len = rgr.len();
+        rgr.release(len);
+    }
 }
 
 /// The "userspace" handle for the driver
 pub struct UsbUartSys {
     out: Producer<'static, USB_BUF_SZ>,
+    out_hi: Producer<'static, USB_HI_BUF_SZ>,
     inc: Consumer<'static, USB_BUF_SZ>,
-    // TODO: There's probably a smarter way to handle this without having
-    // a bigass accumulator struct in here. Either limit max size, or use
-    // a smarter stream decoder which can emit partial data on the fly
-    acc: Accumulator<1024>,
+    // Frames are decoded as they arrive, so they can be any size
+    dec: StreamDecoder,
 
     // Also, we might want to "coverge" older messages into fewer allocs,
     // to avoid small chunks filling up the queue
     ports: LinearMap<u16, Deque<HeapArray<u8>, 16>, 8>,
+
+    // The port currently in `PortMode::Raw`, if any
+    raw_port: Option<u16>,
+
+    // Ports set to `PortPriority::High`. All others are `Normal`.
+    high_priority: Vec<u16, 8>,
+
+    // Ports with a send rate limit. All others are unlimited.
+    rate_limits: LinearMap<u16, TokenBucket, 8>,
+
+    // Ports with their own CDC-ACM interface, which bypass all of the above
+    dedicated: Vec<DedicatedSys, MAX_DEDICATED>,
+
+    // Number of delimited frames which failed to decode
+    framing_errors: u32,
+
+    // Number of messages dropped because the destination port was full
+    overruns: u32,
+}
+
+/// A send rate limit for one port.
+///
+/// Tokens (bytes) accumulate at `rate` per second, up to one second's worth,
+/// so a port that has been quiet may send a burst of up to `rate` bytes.
+struct TokenBucket {
+    rate: u32,
+    tokens: u32,
+    // In `now64()` ticks
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            // Start full, so the first send isn't held back
+            tokens: rate,
+            last_refill: now64(),
+        }
+    }
+
+    /// Add the tokens earned since the last refill, and return the total
+    fn refill(&mut self) -> u32 {
+        let now = now64();
+        let rate = self.rate as u64;
+        let elapsed = now.saturating_sub(self.last_refill);
+        let earned = (elapsed * rate) / 1_000_000;
+
+        // Only move forward by the time it took to earn whole tokens, so
+        // the time towards the next one isn't lost. Otherwise frequent
+        // refills at a low rate would never earn anything.
+        self.last_refill += (earned * 1_000_000) / rate;
+        self.tokens = (self.tokens as u64 + earned).min(rate) as u32;
+
+        // Time spent full earns nothing, so don't let it pile up (and
+        // overflow the math above)
+        if self.tokens == self.rate {
+            self.last_refill = now;
+        }
+        self.tokens
+    }
+
+    fn spend(&mut self, amt: u32) {
+        self.tokens = self.tokens.saturating_sub(amt);
+    }
 }
 
 /// A struct containing both the "interrupt" and "userspace" handles
@@ -95,11 +491,58 @@ pub struct UsbUartParts {
 
 /// Obtain the "userspace" and "interrupt" portions of the USB-Serial driver
 ///
+/// All ports are multiplexed over the single `ser` interface, using sportty
+/// framing. To use the link as a plain byte stream from boot instead, call
+/// `set_port_mode(0, PortMode::Raw)` on the returned `sys` half.
+///
 /// This only returns `Ok` once, as this driver is a singleton. Subsequent
-/// calls will return an `Err`.
+/// calls will return an `Err`, unless the driver is torn down first with
+/// [teardown_usb_uart].
 pub fn setup_usb_uart(dev: AUsbDevice, ser: ASerialPort) -> Result<UsbUartParts, ()> {
+    setup_usb_uart_dedicated(dev, ser, Vec::new())
+}
+
+/// Like [setup_usb_uart], but additionally gives some ports their own
+/// CDC-ACM interface, instead of multiplexing them over `ser`.
+///
+/// Dedicated ports are always registered, can't be released, and are
+/// NOT available over the multiplexed interface. Port 0 can't be dedicated.
+///
+/// NOTE: With more than one CDC-ACM interface, the `UsbDevice` must be built
+/// as a composite device, using `.composite_with_iads()` rather than
+/// `.device_class(USB_CLASS_CDC)`, and every `SerialPort` must be allocated
+/// from the same `UsbBusAllocator` before the device is built.
+///
+/// NOTE: The kernel's `main.rs` doesn't use this yet: it only sets up the
+/// multiplexed interface (with [setup_usb_uart]), so host tools keep seeing
+/// a single serial device. This is for boards that want more.
+pub fn setup_usb_uart_dedicated(
+    dev: AUsbDevice,
+    ser: ASerialPort,
+    dedicated: Vec<DedicatedPort, MAX_DEDICATED>,
+) -> Result<UsbUartParts, ()> {
+    // Check for duplicates and reserved ports before we split any queues
+    for (i, ded) in dedicated.iter().enumerate() {
+        let dupe = dedicated.iter().skip(i + 1).any(|d| d.port == ded.port);
+        if dupe || ded.port == 0 || ded.port > MAX_PORT {
+            return Err(());
+        }
+    }
+
     let (inc_prod, inc_cons) = UART_INC.try_split().map_err(drop)?;
     let (out_prod, out_cons) = UART_OUT.try_split().map_err(drop)?;
+    let (out_hi_prod, out_hi_cons) = UART_OUT_HI.try_split().map_err(drop)?;
+
+    let mut ded_isr = Vec::new();
+    let mut ded_sys = Vec::new();
+    for (i, ded) in dedicated.into_iter().enumerate() {
+        let (inc_prod, inc_cons) = DEDICATED_INC[i].try_split().map_err(drop)?;
+        let (out_prod, out_cons) = DEDICATED_OUT[i].try_split().map_err(drop)?;
+
+        // Can't fail, capacities are identical
+        ded_isr.push(DedicatedIsr { ser: ded.ser, out: out_cons, inc: inc_prod }).ok();
+        ded_sys.push(DedicatedSys { port: ded.port, out: out_prod, inc: inc_cons }).ok();
+    }
 
     // Port zero (stdio) is always mapped.
     let mut ports = LinearMap::new();
@@ -110,96 +553,408 @@ pub fn setup_usb_uart(dev: AUsbDevice, ser: ASerialPort) -> Result<UsbUartParts,
             dev,
             ser,
             out: out_cons,
+            out_hi: out_hi_cons,
             inc: inc_prod,
+            last_state: UsbDeviceState::Default,
+            dedicated: ded_isr,
+            mid_frame: None,
         },
         sys: UsbUartSys {
             out: out_prod,
+            out_hi: out_hi_prod,
             inc: inc_cons,
-            acc: Accumulator::new(),
+            dec: StreamDecoder::new(),
             ports,
+            raw_port: None,
+            high_priority: Vec::new(),
+            rate_limits: LinearMap::new(),
+            dedicated: ded_sys,
+            framing_errors: 0,
+            overruns: 0,
         }
     })
 }
 
+/// Tear down the USB-Serial driver, giving back the USB device and serial
+/// interfaces, so that [setup_usb_uart] (or [setup_usb_uart_dedicated])
+/// can be called again, e.g. to re-initialize USB after a fault, without a
+/// full reboot.
+///
+/// Any queued data, in either direction, is discarded.
+///
+/// # Safety constraints
+///
+/// The static queues may only be reused once nothing can touch the old
+/// halves anymore. Taking `parts` by value ensures that, as long as:
+///
+/// * The USB interrupt is not running, and will not run `poll()` on the
+///   old ISR half. (It must have been handed back, not left in an RTIC
+///   `local`.)
+/// * The "userspace" half wasn't leaked (e.g. into a `&'static mut dyn
+///   Serial`), as a leaked half can never be given back.
+///
+/// On error: some of the queues couldn't be released (which can only
+/// happen if a grant was leaked). These stay in use, so a later setup will
+/// fail, but the rest of the driver is torn down.
+pub fn teardown_usb_uart(
+    parts: UsbUartParts,
+) -> Result<(AUsbDevice, ASerialPort, Vec<DedicatedPort, MAX_DEDICATED>), ()> {
+    let UsbUartParts { isr, sys } = parts;
+
+    // The panic handler must not reach for the old ISR half anymore
+    PANIC_ISR.store(null_mut(), Ordering::Relaxed);
+
+    let mut released = true;
+    released &= UART_INC.try_release(isr.inc, sys.inc).is_ok();
+    released &= UART_OUT.try_release(sys.out, isr.out).is_ok();
+    released &= UART_OUT_HI.try_release(sys.out_hi, isr.out_hi).is_ok();
+
+    // Both halves were built from the same `dedicated` list, so index `i`
+    // in each belongs to `DEDICATED_*[i]`
+    let mut dedicated = Vec::new();
+    for (i, (ded_isr, ded_sys)) in isr.dedicated.into_iter().zip(sys.dedicated.into_iter()).enumerate() {
+        released &= DEDICATED_INC[i].try_release(ded_isr.inc, ded_sys.inc).is_ok();
+        released &= DEDICATED_OUT[i].try_release(ded_sys.out, ded_isr.out).is_ok();
+
+        // Can't fail, capacities are identical
+        dedicated.push(DedicatedPort { port: ded_sys.port, ser: ded_isr.ser }).ok();
+    }
+
+    // Nothing is left of the old session
+    USB_RESET.store(false, Ordering::SeqCst);
+    OUT_FLUSH.store(false, Ordering::SeqCst);
+    USB_LINK_STATE.store(LinkState::Detached as u8, Ordering::SeqCst);
+    for cell in LINE_CODINGS.iter() {
+        cell.reset();
+    }
+
+    if released {
+        Ok((isr.dev, isr.ser, dedicated))
+    } else {
+        Err(())
+    }
+}
+
+impl UsbUartSys {
+    /// If the link was reset, any incoming data (and any partially decoded
+    /// frame) is from the old session. Throw it away, for the dedicated
+    /// interfaces too. Call this before looking at any incoming queue.
+    fn handle_reset(&mut self) {
+        if USB_RESET.swap(false, Ordering::SeqCst) {
+            self.dec.reset();
+            drain(&mut self.inc);
+            for ded in self.dedicated.iter_mut() {
+                drain(&mut ded.inc);
+            }
+        }
+    }
+
+    fn dedicated_mut(&mut self, port: u16) -> Option<&mut DedicatedSys> {
+        self.dedicated.iter_mut().find(|d| d.port == port)
+    }
+}
+
 // Implement the "userspace" traits for the USB UART
 impl crate::traits::Serial for UsbUartSys {
-    fn register_port(&mut self, port: u16) -> Result<(), ()> {
-        if self.ports.contains_key(&port) {
-            return Err(());
+    fn register_port(&mut self, port: u16) -> Result<(), SerialError> {
+        // The top bit of the port is reserved for fragmentation
+        if port > MAX_PORT {
+            return Err(SerialError::InvalidPort);
+        }
+        if self.ports.contains_key(&port) || self.dedicated_mut(port).is_some() {
+            return Err(SerialError::PortInUse);
         }
 
-        self.ports.insert(port, Deque::new()).map_err(drop)?;
+        self.ports.insert(port, Deque::new()).map_err(|_| SerialError::TooManyPorts)?;
 
-        defmt::println!("Registered port {=u16}!", port);
+        log_debug!("Registered port {=u16}!", port);
 
         Ok(())
     }
 
-    fn release_port(&mut self, port: u16) -> Result<(), ()> {
+    fn available(&mut self, port: u16) -> Result<usize, SerialError> {
+        self.handle_reset();
+        if let Some(ded) = self.dedicated_mut(port) {
+            // NOTE: This only counts the first contiguous region of a
+            // wrapped-around queue, which is good enough to tell "some"
+            // from "none".
+            return Ok(ded.inc.read().map(|rgr| rgr.len()).unwrap_or(0));
+        }
+
+        self.process();
+
+        let deq = self.ports.get(&port).ok_or(SerialError::NoSuchPort)?;
+        Ok(deq.iter().map(|msg| msg.len()).sum())
+    }
+
+    fn send_space(&mut self, port: u16) -> Result<usize, SerialError> {
+        if let Some(ded) = self.dedicated_mut(port) {
+            return Ok(free_space(&mut ded.out));
+        }
+
+        if !self.ports.contains_key(&port) {
+            return Err(SerialError::NoSuchPort);
+        }
+
+        // Same rules as `send()`
+        let space = match self.raw_port {
+            Some(raw) if raw == port && OUT_FLUSH.load(Ordering::SeqCst) => 0,
+            Some(raw) if raw == port => free_space(&mut self.out),
+            Some(_) => return Ok(0),
+            None => {
+                let free = if self.high_priority.contains(&port) {
+                    free_space(&mut self.out_hi)
+                } else {
+                    free_space(&mut self.out)
+                };
+
+                // What fits in a single frame, after the port number, the
+                // COBS overhead, and the terminator
+                max_data_length(free)
+            }
+        };
+
+        match self.rate_limits.get_mut(&port).map(TokenBucket::refill) {
+            Some(budget) => Ok(space.min(budget as usize)),
+            None => Ok(space),
+        }
+    }
+
+    fn wait_data(&mut self, port: u16, deadline: Option<u64>) -> Result<Option<usize>, SerialError> {
+        loop {
+            // Clear the flag BEFORE checking, so we can't miss data that
+            // arrives between the check and going to sleep.
+            USB_DATA.store(false, Ordering::SeqCst);
+
+            let avail = self.available(port)?;
+            if avail != 0 {
+                return Ok(Some(avail));
+            }
+
+            // Nothing wakes us up when the deadline passes, so with a
+            // deadline, we have to keep polling instead of sleeping.
+            if let Some(deadline) = deadline {
+                if now64() >= deadline {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            // With interrupts masked, WFI still wakes on a pending
+            // interrupt, and the ISR runs as soon as we unmask. This closes
+            // the gap between checking the flag and sleeping.
+            cortex_m::interrupt::free(|_| {
+                if !USB_DATA.load(Ordering::SeqCst) {
+                    cortex_m::asm::wfi();
+                }
+            });
+        }
+    }
+
+    fn release_port(&mut self, port: u16) -> Result<(), SerialError> {
+        // Port 0 is always open
         if port == 0 {
-            return Err(());
+            return Err(SerialError::Unsupported);
         }
 
         if self.ports.remove(&port).is_some() {
+            if self.raw_port == Some(port) {
+                self.raw_port = None;
+            }
+            self.high_priority.retain(|p| *p != port);
+            self.rate_limits.remove(&port);
             Ok(())
         } else {
-            Err(())
+            Err(SerialError::NoSuchPort)
+        }
+    }
+
+    fn release_all(&mut self) -> usize {
+        let ports: Vec<u16, 8> = self.ports.keys().copied().filter(|p| *p != 0).collect();
+        for port in ports.iter() {
+            // Can't fail, the port is registered and isn't port 0
+            self.release_port(*port).ok();
+        }
+        ports.len()
+    }
+
+    fn list_ports(&mut self, out: &mut [PortInfo]) -> usize {
+        self.handle_reset();
+        let muxed = self.ports.iter().map(|(port, deq)| PortInfo {
+            port: *port,
+            queued_msgs: deq.len() as u16,
+            queued_bytes: deq.iter().map(|msg| msg.len() as u32).sum(),
+        });
+
+        // NOTE: As in `available()`, this only counts the first contiguous
+        // region of a dedicated port's queue
+        let dedicated = self.dedicated.iter_mut().map(|ded| {
+            let queued = ded.inc.read().map(|rgr| rgr.len()).unwrap_or(0);
+            PortInfo {
+                port: ded.port,
+                queued_msgs: if queued == 0 { 0 } else { 1 },
+                queued_bytes: queued as u32,
+            }
+        });
+
+        let mut count = 0;
+        for (slot, info) in out.iter_mut().zip(muxed.chain(dedicated)) {
+            *slot = info;
+            count += 1;
+        }
+        count
+    }
+
+    fn set_port_rate(&mut self, port: u16, bytes_per_sec: u32) -> Result<(), SerialError> {
+        if !self.ports.contains_key(&port) {
+            return Err(SerialError::NoSuchPort);
+        }
+
+        self.rate_limits.remove(&port);
+        if bytes_per_sec != 0 {
+            // Can't fail, there can't be more limited ports than ports
+            self.rate_limits.insert(port, TokenBucket::new(bytes_per_sec)).ok();
+        }
+        Ok(())
+    }
+
+    fn set_port_priority(&mut self, port: u16, priority: PortPriority) -> Result<(), SerialError> {
+        if !self.ports.contains_key(&port) {
+            return Err(SerialError::NoSuchPort);
+        }
+
+        self.high_priority.retain(|p| *p != port);
+        if priority == PortPriority::High {
+            // Can't fail, there can't be more high priority ports than ports
+            self.high_priority.push(port).ok();
+        }
+        Ok(())
+    }
+
+    fn set_port_mode(&mut self, port: u16, mode: PortMode) -> Result<(), SerialError> {
+        if !self.ports.contains_key(&port) {
+            return Err(SerialError::NoSuchPort);
+        }
+
+        match (mode, self.raw_port) {
+            // Only one port can own the raw link at a time
+            (PortMode::Raw, Some(raw)) if raw != port => return Err(SerialError::PortInUse),
+            (PortMode::Raw, Some(_)) => {},
+            (PortMode::Raw, None) => {
+                // Any partially decoded frame is meaningless now, and any
+                // queued outgoing frames can't be sent without corrupting
+                // the raw stream. The ISR discards them on its next poll.
+                self.dec.reset();
+                OUT_FLUSH.store(true, Ordering::SeqCst);
+                self.raw_port = Some(port);
+            },
+            (PortMode::Framed, Some(raw)) if raw == port => {
+                self.raw_port = None;
+            },
+            (PortMode::Framed, _) => {},
+        }
+
+        Ok(())
+    }
+
+    fn line_coding(&mut self, port: u16) -> Result<LineCoding, SerialError> {
+        // Dedicated ports have their own interface, the rest share one
+        if let Some(i) = self.dedicated.iter().position(|d| d.port == port) {
+            return Ok(LINE_CODINGS[i + 1].get());
+        }
+        if !self.ports.contains_key(&port) {
+            return Err(SerialError::NoSuchPort);
+        }
+        Ok(LINE_CODINGS[0].get())
+    }
+
+    fn link_state(&self) -> (LinkState, u32) {
+        let state = match USB_LINK_STATE.load(Ordering::SeqCst) {
+            x if x == LinkState::Configured as u8 => LinkState::Configured,
+            x if x == LinkState::Suspended as u8 => LinkState::Suspended,
+            _ => LinkState::Detached,
+        };
+        (state, USB_SUSPENDS.load(Ordering::SeqCst))
+    }
+
+    fn port_stats(&mut self, port: u16) -> Result<PortStats, SerialError> {
+        // Dedicated ports can't be rate limited
+        if self.dedicated_mut(port).is_some() {
+            let queued_bytes = self.available(port)? as u32;
+            return Ok(PortStats { queued_bytes, send_budget: None });
+        }
+
+        let deq = self.ports.get(&port).ok_or(SerialError::NoSuchPort)?;
+        let queued_bytes = deq.iter().map(|msg| msg.len() as u32).sum();
+        let send_budget = self.rate_limits.get_mut(&port).map(TokenBucket::refill);
+        Ok(PortStats { queued_bytes, send_budget })
+    }
+
+    fn link_stats(&self) -> LinkStats {
+        LinkStats {
+            framing_errors: self.framing_errors,
+            overruns: self.overruns,
         }
     }
 
     fn process(&mut self) {
+        self.handle_reset();
+
+        // In raw mode, there is no framing at all. Hand everything over to
+        // the raw port as-is.
+        if let Some(port) = self.raw_port {
+            while let Ok(rgr) = self.inc.read() {
+                if let Err(err) = enqueue_incoming(&mut self.ports, port, &rgr) {
+                    self.overruns = self.overruns.wrapping_add(1);
+                    log_warn!("Failed to receive raw data for serial port {=u16}: {}. Discarding.", port, err);
+                }
+                let rec_len = rgr.len();
+                rgr.release(rec_len);
+            }
+            return;
+        }
+
         // Process all incoming message and dispatch to queues
-        'outer: while let Ok(rgr) = self.inc.read() {
+        while let Ok(rgr) = self.inc.read() {
             let mut window = rgr.deref();
             let rec_len = rgr.len();
 
             //////////////////////
             // No early returns here! We need to release the grant!
             while !window.is_empty() {
-                match self.acc.feed(window) {
-                    Ok(Some(mut msg)) => {
-                        match Message::decode_in_place(msg.msg.as_mut_slice()) {
-                            Ok(smsg) => {
-                                // defmt::println!("Decoded port {=u16} - msg: {=[u8]}", smsg.port, smsg.data);
-
-                                // If this is port 0, then (try to) also loopback!
-                                // #[cfg(feature = "auto-loopback")]
-                                if smsg.port == 0 {
-                                    self.send(0, &smsg.data).ok();
-                                }
-
-                                // TODO: Replace this with `map()` and Results so we can actually
-                                // tell which part went wrong
-                                let failed = self.ports
-                                    .get_mut(&smsg.port)
-                                    .and_then(|dq| {
-                                        // Keep the heap locked for as short as possible!
-                                        let mut hp = HEAP.try_lock()?;
-                                        let habox = hp.alloc_box_array(0u8, smsg.data.len()).ok()?;
-                                        Some((dq, habox))
-                                    })
-                                    .and_then(|(dq, mut habox)| {
-                                        habox.copy_from_slice(&smsg.data);
-                                        dq.push_back(habox).ok()
-                                    }).is_none();
-
-                                if failed && self.ports.contains_key(&smsg.port) {
-                                    defmt::println!("Failed to receive message for serial port {=u16}. Discarding.", smsg.port);
-                                }
-                            },
-                            Err(_) => defmt::println!("Sportty error!"),
+                let mut scratch = [0u8; 256];
+                let dec = self.dec.feed(window, &mut scratch);
+                window = dec.remainder;
+
+                // Ports are byte streams, so data is queued on the logical
+                // port in chunks as soon as it is decoded. The decoder has
+                // already stripped the continuation bit: concatenating the
+                // chunks in order is all the reassembly a byte stream needs,
+                // so message boundaries are not kept.
+                if let (Some(port), false) = (dec.port, dec.data.is_empty()) {
+                    // If this is port 0, then (try to) also loopback!
+                    // #[cfg(feature = "auto-loopback")]
+                    if port == 0 {
+                        self.send(0, dec.data).ok();
+                    }
+
+                    match enqueue_incoming(&mut self.ports, port, dec.data) {
+                        // Nobody is listening, that's fine
+                        Ok(()) | Err(EnqueueError::NoPort) => {},
+                        Err(err) => {
+                            self.overruns = self.overruns.wrapping_add(1);
+                            log_warn!("Failed to receive message for serial port {=u16}: {}. Discarding.", port, err);
                         }
-                        window = msg.remainder;
-                    },
-                    Ok(None) => {},
-                    Err(AccError::NoRoomNoRem) => {
-                        rgr.release(rec_len);
-                        continue 'outer;
-                    },
-                    Err(AccError::NoRoomWithRem(rem)) => {
-                        window = rem;
                     }
                 }
+
+                if dec.status == Status::Error {
+                    // The frame was delimited, but didn't decode. Most
+                    // likely, bytes were lost on the link. Whatever was
+                    // already queued from it can't be taken back.
+                    self.framing_errors = self.framing_errors.wrapping_add(1);
+                    log_warn!("Sportty error!");
+                }
             }
 
             rgr.release(rec_len);
@@ -208,10 +963,18 @@ impl crate::traits::Serial for UsbUartSys {
         }
     }
 
-    fn recv<'a>(&mut self, port: u16, buf: &'a mut [u8]) -> Result<&'a mut [u8], ()> {
+    fn recv<'a>(&mut self, port: u16, buf: &'a mut [u8]) -> Result<&'a mut [u8], SerialError> {
         self.process();
+        self.recv_nowait(port, buf)
+    }
 
-        let deq = self.ports.get_mut(&port).ok_or(())?;
+    fn recv_nowait<'a>(&mut self, port: u16, buf: &'a mut [u8]) -> Result<&'a mut [u8], SerialError> {
+        self.handle_reset();
+        if let Some(ded) = self.dedicated_mut(port) {
+            return Ok(ded.recv(buf));
+        }
+
+        let deq = self.ports.get_mut(&port).ok_or(SerialError::NoSuchPort)?;
         let mut used = 0;
         let buflen = buf.len();
 
@@ -233,12 +996,24 @@ impl crate::traits::Serial for UsbUartSys {
                 buf[used..][..msg.len()].copy_from_slice(&msg);
                 used += msg.len();
             } else {
+                // We need a new allocation to hold the part that doesn't fit.
+                // If the heap is busy or exhausted, put the whole message back
+                // and just hand over what we have so far. The caller can try
+                // again later, once there is some room.
                 let (now, later) = msg.split_at(avail);
-                buf[used..].copy_from_slice(now);
+                let habox = HEAP.try_lock().and_then(|mut hp| {
+                    hp.alloc_box_from_slice(later).ok()
+                });
+                let habox = match habox {
+                    Some(habox) => habox,
+                    None => {
+                        // Okay to ignore error - We just made space
+                        deq.push_front(msg).ok();
+                        return Ok(&mut buf[..used]);
+                    }
+                };
 
-                let mut hp = defmt::unwrap!(HEAP.try_lock());
-                let mut habox = defmt::unwrap!(hp.alloc_box_array(0u8, later.len()).ok());
-                habox.copy_from_slice(later);
+                buf[used..].copy_from_slice(now);
 
                 // Okay to ignore error - We just made space
                 deq.push_front(habox).ok();
@@ -251,89 +1026,269 @@ impl crate::traits::Serial for UsbUartSys {
         Ok(buf)
     }
 
+    fn recv_owned(&mut self, port: u16) -> Result<Option<HeapArray<u8>>, SerialError> {
+        // Dedicated ports are plain byte queues, with no allocations to hand out
+        if self.dedicated_mut(port).is_some() {
+            return Err(SerialError::Unsupported);
+        }
+
+        self.process();
+
+        let deq = self.ports.get_mut(&port).ok_or(SerialError::NoSuchPort)?;
+        Ok(deq.pop_front())
+    }
+
     fn send<'a>(&mut self, port: u16, buf: &'a [u8]) -> Result<(), &'a [u8]> {
+        if let Some(ded) = self.dedicated_mut(port) {
+            return ded.send(buf);
+        }
+
         // Check if port is mapped
         if !self.ports.contains_key(&port) {
-            defmt::println!("Unregistered port: {=u16}", port);
+            log_debug!("Unregistered port: {=u16}", port);
             return Err(buf);
         }
 
-        let mut remaining = buf;
+        // A rate limited port may only send what's left of its budget. Going
+        // over looks just like a full queue to the caller.
+        let now = match self.rate_limits.get_mut(&port).map(TokenBucket::refill) {
+            Some(0) => return Err(buf),
+            Some(budget) => &buf[..buf.len().min(budget as usize)],
+            None => buf,
+        };
+
+        // A raw port owns the whole link. Its data goes out as-is, and
+        // frames from any other port would just be noise to the other end.
+        let res = match self.raw_port {
+            // Wait for the ISR to flush the queues, see `set_port_mode()`
+            Some(raw) if raw == port && OUT_FLUSH.load(Ordering::SeqCst) => return Err(buf),
+            Some(raw) if raw == port => enqueue_raw(&mut self.out, now),
+            Some(_) => return Err(buf),
+            None if self.high_priority.contains(&port) => enqueue_frames(&mut self.out_hi, port, now),
+            None => enqueue_frames(&mut self.out, port, now),
+        };
+
+        // Both enqueue functions send a prefix of the data
+        let sent = now.len() - res.err().map(|rem| rem.len()).unwrap_or(0);
+        if let Some(tb) = self.rate_limits.get_mut(&port) {
+            tb.spend(sent as u32);
+        }
+
+        if sent == buf.len() {
+            Ok(())
+        } else {
+            Err(&buf[sent..])
+        }
+    }
+}
+
+impl DedicatedSys {
+    fn recv<'a>(&mut self, buf: &'a mut [u8]) -> &'a mut [u8] {
+        let mut used = 0;
+
+        // Loop, to pick up both halves of a wrapped-around queue
+        while used < buf.len() {
+            let rgr = match self.inc.read() {
+                Ok(rgr) => rgr,
+                Err(_) => break,
+            };
+            let amt = rgr.len().min(buf.len() - used);
+            buf[used..][..amt].copy_from_slice(&rgr[..amt]);
+            rgr.release(amt);
+            used += amt;
+        }
+
+        &mut buf[..used]
+    }
 
-        // We loop here, as the bbqueue may be in a "wraparound" situation,
-        // where there is only a little space available at the "tail" of the
-        // ring buffer, but there is space available at the front. This will
-        // generally only execute once (no wraparound) or twice (some wraparound),
-        // unless the driver clears some more space while we are processing.
-        while !remaining.is_empty() {
-            let rem_len = max_encoding_length(remaining.len());
+    fn send<'a>(&mut self, buf: &'a [u8]) -> Result<(), &'a [u8]> {
+        enqueue_raw(&mut self.out, buf)
+    }
+}
 
-            // Attempt to get a write grant to send to the driver...
-            match self.out.grant_max_remaining(rem_len) {
-                // Can we write the port and AT LEAST one byte of data
-                // and a null terminator?
-                Ok(wgr) if wgr.len() <= (2 + 1 + 1) => {
+/// The contiguous free space in an outgoing queue, without using any of it
+///
+/// NOTE: Like `available()`, this ignores the second half of a wrapped-around
+/// queue, so it may be less than the total free space.
+fn free_space<const N: usize>(out: &mut Producer<'_, N>) -> usize {
+    // Dropping the grant without committing leaves the queue untouched
+    out.grant_max_remaining(N).map(|wgr| wgr.len()).unwrap_or(0)
+}
+
+/// Copy `buf` into the outgoing queue as-is, without any framing
+///
+/// On error, returns the part of `buf` that didn't fit.
+fn enqueue_raw<'a, const N: usize>(out: &mut Producer<'_, N>, buf: &'a [u8]) -> Result<(), &'a [u8]> {
+    let mut remaining = buf;
+
+    // Loop, to make use of both halves of a wrapped-around queue
+    while !remaining.is_empty() {
+        let mut wgr = match out.grant_max_remaining(remaining.len()) {
+            Ok(wgr) => wgr,
+            Err(_) => return Err(remaining),
+        };
+        let amt = wgr.len();
+        let (now, later) = remaining.split_at(amt);
+        wgr.copy_from_slice(now);
+        wgr.commit(amt);
+        remaining = later;
+    }
+
+    Ok(())
+}
+
+/// Why incoming data couldn't be queued for a port
+#[derive(defmt::Format, Debug, PartialEq)]
+enum EnqueueError {
+    /// The port isn't registered
+    NoPort,
+    /// The heap was locked. This only runs in the syscall handler, which is
+    /// the only context that allocates, so this means a bug (see
+    /// `crate::alloc`), not contention.
+    HeapBusy,
+    /// The heap has no room for the data
+    HeapFull,
+    /// The port already has as many messages queued as it can hold
+    QueueFull,
+}
+
+/// Copy `data` into a new allocation, and queue it for `port`.
+fn enqueue_incoming(
+    ports: &mut LinearMap<u16, Deque<HeapArray<u8>, 16>, 8>,
+    port: u16,
+    data: &[u8],
+) -> Result<(), EnqueueError> {
+    let dq = ports.get_mut(&port).ok_or(EnqueueError::NoPort)?;
+    let habox = {
+        // Keep the heap locked for as short as possible!
+        let mut hp = HEAP.try_lock().ok_or(EnqueueError::HeapBusy)?;
+        hp.alloc_box_from_slice(data).map_err(|_| EnqueueError::HeapFull)?
+    };
+
+    // If this fails, the allocation is freed again on drop
+    dq.push_back(habox).map_err(|_| EnqueueError::QueueFull)
+}
+
+/// Encode `buf` as one or more sportty frames for `port`, and commit them
+/// to the outgoing queue.
+///
+/// On success: All bytes were enqueued.
+/// On error: the portion of bytes that were NOT enqueued (the remainder).
+///
+/// If `buf` needs more than one frame, all but the last frame are marked with
+/// the sportty continuation bit. If the queue fills up part way through, the
+/// frames that were enqueued are closed off with an empty, unmarked frame, so
+/// a logical message never stays open. Sending the remainder later starts a
+/// new logical message.
+///
+/// Running out of room in the queue is a normal, recoverable condition, and
+/// is reported as an error containing the remainder, so the caller can try
+/// again later. Only bbqueue errors that indicate a logic bug will panic.
+pub fn enqueue_frames<'a, const N: usize>(
+    out: &mut Producer<'_, N>,
+    port: u16,
+    buf: &'a [u8],
+) -> Result<(), &'a [u8]> {
+    // The size of an empty frame, used to close off a partially sent message
+    let close_len = max_encoding_length(0);
+    let mut remaining = buf;
+
+    // Is the last committed frame marked as continued?
+    let mut open = false;
+
+    // We loop here, as the bbqueue may be in a "wraparound" situation,
+    // where there is only a little space available at the "tail" of the
+    // ring buffer, but there is space available at the front. This will
+    // generally only execute once (no wraparound) or twice (some wraparound),
+    // unless the driver clears some more space while we are processing.
+    while !remaining.is_empty() {
+        let rem_len = max_encoding_length(remaining.len());
+
+        // Attempt to get a write grant to send to the driver...
+        match out.grant_max_remaining(rem_len) {
+            // We got some (or all) necessary space.
+            // Copy the relevant data, and slide the window over.
+            // (If this was "all", then `remaining` will be empty)
+            Ok(mut wgr) => {
+                // If only part fits, keep enough room after this frame to
+                // close off the message, in case the rest doesn't fit either.
+                // The reserved room directly follows this frame, so the next
+                // grant can never be smaller than that.
+                let fits = if wgr.len() >= rem_len {
+                    remaining.len()
+                } else {
+                    max_data_length(wgr.len().saturating_sub(close_len)).min(remaining.len())
+                };
+
+                if fits == 0 {
+                    // Not even one byte of data fits. Close off what we have
+                    // already sent with an empty frame. The space for it was
+                    // reserved when the previous frame was committed.
+                    if open {
+                        let msg = Message::fragment(port, &[], false);
+                        if let Ok(used) = msg.encode_to(&mut wgr).map(|used| used.len()) {
+                            wgr.commit(used);
+                        } else {
+                            log_error!("Failed to close a partially sent message!");
+                        }
+                    }
                     return Err(remaining);
                 }
 
-                // We have exhausted the available size in the outgoing buffer.
-                // Give the user the remaining, unsent part, so they can try again
-                // later.
-                Err(bbqueue::Error::InsufficientSize) => {
-                    return Err(remaining);
-                },
+                let (now, later) = remaining.split_at(fits);
 
-                // We got some (or all) necessary space.
-                // Copy the relevant data, and slide the window over.
-                // (If this was "all", then `remaining` will be empty)
-                Ok(mut wgr) => {
-                    // We should take the lesser of:
-                    //
-                    // * The grant length, minus three overhead bytes (two for port,
-                    //     one for sentinel), which is always positive due to check
-                    //     above, OR
-                    // * The remaining data length
-                    let to_use = (wgr.len() - 4).min(remaining.len());
-                    let (now, later) = remaining.split_at(to_use);
-
-                    // Setup and encode the message
-                    let msg = Message { port, data: now };
-
-                    // This SHOULD never fail, make it an assert for now to catch dumb errors
-                    let used = match msg.encode_to(&mut wgr) {
-                        Ok(used) => used.len(),
-                        Err(_) => {
-                            defmt::println!("Encoding failure!");
-                            defmt::println!("remaining len: {=usize}", remaining.len());
-                            defmt::println!("wgr len: {=usize}", wgr.len());
-                            defmt::println!("now len: {=usize}", now.len());
-                            defmt::println!("remaining: {=[u8]}", remaining);
-                            defmt::println!("now: {=[u8]}", now);
-                            defmt::panic!();
-                        },
-                    };
-
-                    // Commit the ENCODED number of bytes, and store the remaining
-                    // UNENCODED bytes
-                    wgr.commit(used);
-                    remaining = later;
-                },
-
-                // This error case generally represents some kind of logic error
-                // such as retaining a grant (our problem), or an internal fault
-                // of bbqueue. Either way, this is not likely to be a recoverable
-                // error. Until we have better fault recovery logic in place,
-                // just panic and get it over with.
-                Err(_e) => {
-                    defmt::panic!("ERROR: USB UART Send!");
-                }
+                // Setup and encode the message. If there is more data left after
+                // this frame, mark it as a fragment of a larger message.
+                let msg = Message::fragment(port, now, !later.is_empty());
+
+                // This SHOULD never fail. If it does, nothing has been committed
+                // yet, so drop the grant and give the caller back everything
+                // we haven't sent rather than taking down the whole device.
+                let used = match msg.encode_to(&mut wgr) {
+                    Ok(used) => used.len(),
+                    Err(_) => {
+                        log_error!("Encoding failure!");
+                        log_error!("remaining len: {=usize}", remaining.len());
+                        log_error!("wgr len: {=usize}", wgr.len());
+                        log_error!("now len: {=usize}", now.len());
+                        return Err(remaining);
+                    },
+                };
+
+                // Commit the ENCODED number of bytes, and store the remaining
+                // UNENCODED bytes
+                wgr.commit(used);
+                remaining = later;
+                open = !later.is_empty();
+            },
+
+            // We have exhausted the available size in the outgoing buffer.
+            // Give the user the remaining, unsent part, so they can try again
+            // later. This can't happen with a message still open, as room to
+            // close it was reserved above.
+            Err(bbqueue::Error::InsufficientSize) => {
+                return Err(remaining);
+            },
+
+            // A grant is already in progress. We never hold a grant across
+            // calls, so this can only mean a grant was retained (leaked)
+            // somewhere, which is a logic error on our part. This is not
+            // recoverable, as no future grant will ever succeed.
+            Err(bbqueue::Error::GrantInProgress) => {
+                defmt::panic!("ERROR: USB UART Send - grant retained!");
             }
-        }
 
-        // This means that we reached `remaining.is_empty()`, and all
-        // data has been successfully sent.
-        Ok(())
+            // `AlreadySplit` is only returned when splitting the queue,
+            // never when granting. Treat it like any other logic error.
+            Err(bbqueue::Error::AlreadySplit) => {
+                defmt::panic!("ERROR: USB UART Send - unexpected split error!");
+            }
+        }
     }
+
+    // This means that we reached `remaining.is_empty()`, and all
+    // data has been successfully sent.
+    Ok(())
 }
 
 pub fn enable_usb_interrupts(usbd: &USBD) {
@@ -365,76 +1320,3 @@ pub fn enable_usb_interrupts(usbd: &USBD) {
         w
     });
 }
-
-
-
-struct Accumulator<const N: usize> {
-    buf: [u8; N],
-    idx: usize,
-}
-
-enum AccError<'a> {
-    NoRoomNoRem,
-    NoRoomWithRem(&'a [u8]),
-}
-
-impl<const N: usize> Accumulator<N> {
-    fn new() -> Self {
-        Self {
-            buf: [0u8; N],
-            idx: 0,
-        }
-    }
-    fn feed<'a>(&mut self, buf: &'a [u8]) -> Result<Option<AccSuccess<'a, N>>, AccError<'a>> {
-        match buf.iter().position(|b| *b == 0) {
-            Some(n) if (self.idx + n) <= N => {
-                let (now, later) = buf.split_at(n + 1);
-                self.buf[self.idx..][..now.len()].copy_from_slice(now);
-                let mut msg = AccMsg {
-                    buf: [0u8; N],
-                    len: self.idx + now.len(),
-                };
-                msg.buf[..msg.len].copy_from_slice(&self.buf[..msg.len]);
-                self.idx = 0;
-                Ok(Some(AccSuccess {
-                    remainder: later,
-                    msg,
-                }))
-            },
-            Some(n) if n < buf.len() => {
-                self.idx = 0;
-                Err(AccError::NoRoomWithRem(&buf[(n + 1)..]))
-            },
-            Some(_) => {
-                self.idx = 0;
-                Err(AccError::NoRoomNoRem)
-            }
-            None if (self.idx + buf.len()) <= N => {
-                self.buf[self.idx..][..buf.len()].copy_from_slice(buf);
-                self.idx += buf.len();
-                Ok(None)
-            },
-            None => {
-                // No room, and no zero. Truncate the current buf.
-                self.idx = 0;
-                Err(AccError::NoRoomNoRem)
-            },
-        }
-    }
-}
-
-struct AccSuccess<'a, const N: usize> {
-    remainder: &'a [u8],
-    msg: AccMsg<N>,
-}
-
-struct AccMsg<const N: usize> {
-    buf: [u8; N],
-    len: usize,
-}
-
-impl<const N: usize> AccMsg<N> {
-    fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.buf[..self.len]
-    }
-}
@@ -14,10 +14,14 @@ use nrf52840_hal::{
         p1::{P1_00, P1_02, P1_08, P1_09, P1_10, P1_15},
         Disconnected,
     },
-    pac::{P0, P1},
+    pac::{P0, P1, POWER},
 }; // memory layout
+use common::{ExitReason, ResetCause};
+use core::{fmt::Write, panic::PanicInfo};
+use heapless::String;
 
-use panic_probe as _;
+#[macro_use]
+pub mod log;
 pub mod qspi;
 pub mod traits;
 pub mod alloc;
@@ -25,11 +29,39 @@ pub mod monotonic;
 pub mod drivers;
 pub mod syscall;
 pub mod loader;
+pub mod version;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 
-// same panicking *behavior* as `panic-probe` but doesn't print a panic message
-// this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+// Like `panic-probe`, but also makes a best-effort attempt to tell the host
+// why we died over USB serial, for users without a debug probe.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    let mut msg: String<256> = String::new();
+    // A truncated message is better than none
+    write!(&mut msg, "PANIC: {}", info).ok();
+    unsafe {
+        drivers::usb_serial::panic_report(msg.as_bytes());
+    }
+
+    cortex_m::asm::udf()
+}
+
+// `defmt::panic!` doesn't go through the regular panic handler. Its message
+// has already been logged (over RTT) by the time we get here, and isn't
+// available as text, so just point the user at the log.
 #[defmt::panic_handler]
-fn panic() -> ! {
+fn defmt_panic() -> ! {
+    cortex_m::interrupt::disable();
+
+    unsafe {
+        drivers::usb_serial::panic_report(b"PANIC: (see defmt log)");
+    }
+
     cortex_m::asm::udf()
 }
 
@@ -40,6 +72,65 @@ pub fn exit() -> ! {
     }
 }
 
+/// Handle an application exit: record the reason in GPREGRET (which is
+/// retained across a soft reset), then halt or reboot as the reason asks.
+pub fn app_exit(reason: ExitReason) -> ! {
+    // SAFETY: GPREGRET is only used for the exit reason
+    let power = unsafe { &*POWER::ptr() };
+    power.gpregret.write(|w| unsafe { w.gpregret().bits(reason as u8) });
+
+    match reason {
+        ExitReason::Success => {
+            // Interrupts (and the USB link) keep running
+            log_info!("Application exited. Halting.");
+            loop {
+                cortex_m::asm::wfi();
+            }
+        }
+        ExitReason::Failure | ExitReason::Panic | ExitReason::Reboot => {
+            cortex_m::peripheral::SCB::sys_reset()
+        }
+    }
+}
+
+/// Take the exit reason recorded before the last reset, if any, clearing it
+pub fn take_exit_reason() -> Option<ExitReason> {
+    // SAFETY: GPREGRET is only used for the exit reason
+    let power = unsafe { &*POWER::ptr() };
+    let val = power.gpregret.read().gpregret().bits();
+    power.gpregret.write(|w| unsafe { w.gpregret().bits(0) });
+    ExitReason::from_u8(val)
+}
+
+/// Take the cause of the last reset from RESETREAS, clearing it.
+///
+/// RESETREAS accumulates until cleared, so without clearing it here, the
+/// next reset would report this cause too.
+pub fn take_reset_cause() -> ResetCause {
+    // SAFETY: RESETREAS is only used here
+    let power = unsafe { &*POWER::ptr() };
+    let reas = power.resetreas.read();
+
+    let cause = if reas.dog().is_detected() {
+        ResetCause::Watchdog
+    } else if reas.lockup().is_detected() {
+        ResetCause::Lockup
+    } else if reas.sreq().is_detected() {
+        ResetCause::SoftReset
+    } else if reas.resetpin().is_detected() {
+        ResetCause::PinReset
+    } else if reas.bits() == 0 {
+        // No bits set means power-on (or brown-out) reset
+        ResetCause::PowerOn
+    } else {
+        ResetCause::Other
+    };
+
+    // Bits are cleared by writing 1 to them
+    power.resetreas.write(|w| unsafe { w.bits(reas.bits()) });
+    cause
+}
+
 pub struct Pins {
     /// HS
     pub a00: P0_04<Disconnected>,
@@ -0,0 +1,52 @@
+//! Kernel logging, filtered by a level set at runtime
+//!
+//! defmt's own levels (`defmt::info!`, etc.) are picked at build time, with
+//! `DEFMT_LOG`. The macros here log with `defmt::println!` instead, but only
+//! if their level is enabled right now, so a running system can be quieted
+//! (or made chattier) with the `SetLogLevel` syscall, without a rebuild.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+pub use common::LogLevel;
+
+/// The most verbose level that is logged, as a `LogLevel` discriminant
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Set the most verbose level that is logged
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Would a message at `level` be logged right now?
+pub fn enabled(level: LogLevel) -> bool {
+    (level as u8) <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Log with `defmt::println!`, if `$level` is enabled
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::$level) {
+            defmt::println!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::log!(Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::log!(Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::log!(Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::log!(Debug, $($arg)*) };
+}
@@ -1,7 +1,14 @@
 #![no_main]
 #![no_std]
 
-static DEFAULT_IMAGE: &[u8] = include_bytes!("../appbins/test.bin");
+/// The application booted on every reset. This is `appbins/test.bin`,
+/// unless overridden with the `PELLEGRINO_DEFAULT_IMAGE` environment
+/// variable at build time (see `build.rs`).
+///
+/// It is the only image the kernel boots, so an app can always get back to
+/// a known-good state with the `BootDefault` syscall, or by exiting with
+/// `ExitReason::Reboot`.
+static DEFAULT_IMAGE: &[u8] = include_bytes!(env!("KERNEL_DEFAULT_IMAGE"));
 
 #[rtic::app(device = nrf52840_hal::pac, dispatchers = [SWI0_EGU0])]
 mod app {
@@ -17,10 +24,12 @@ mod app {
     };
     use kernel::{
         alloc::HEAP,
-        monotonic::{MonoTimer},
+        monotonic::{MonoTimer, ExtU32},
         drivers::usb_serial::{UsbUartParts, setup_usb_uart, UsbUartIsr, enable_usb_interrupts},
+        drivers::heartbeat::{Heartbeat, HEARTBEAT_TICK_MS},
         syscall::{syscall_clear, try_recv_syscall},
-        loader::validate_header,
+        loader::{validate_header, oc_flash_setup},
+        log_info, log_warn, log_error,
     };
     use usb_device::{
         class_prelude::UsbBusAllocator,
@@ -28,7 +37,7 @@ mod app {
     };
     use usbd_serial::{SerialPort, USB_CLASS_CDC};
     use groundhog::RollingTimer;
-    use super::{DEFAULT_IMAGE, letsago};
+    use super::{DEFAULT_IMAGE, launch_app};
 
     #[monotonic(binds = TIMER0, default = true)]
     type Monotonic = MonoTimer<TIMER0>;
@@ -40,6 +49,7 @@ mod app {
     struct Local {
         usb_isr: UsbUartIsr,
         machine: kernel::traits::Machine,
+        heartbeat: Heartbeat,
     }
 
     #[init]
@@ -61,9 +71,38 @@ mod app {
         // Setup the heap
         HEAP.init().ok();
 
+        // Report why we booted, and how the previous application exited, if
+        // it did. Both are cleared here, but kept for the `ResetReason` syscall.
+        let reset_cause = kernel::take_reset_cause();
+        let exit_reason = kernel::take_exit_reason();
+        log_info!("Reset cause: {}", reset_cause);
+        if let Some(reason) = exit_reason {
+            log_info!("Previous application exit reason: {=u8}", reason as u8);
+        }
+
         // Reset the syscall contents
         syscall_clear();
 
+        let pins = kernel::map_pins(device.P0, device.P1);
+
+        // Halts here (blinking led2) if the hardware is broken
+        #[cfg(feature = "selftest")]
+        let flash_jedec_id = {
+            let qspi_pins = kernel::qspi::QspiPins {
+                qspi_copi_io0: pins.qspi_d0.degrade(),
+                qspi_cipo_io1: pins.qspi_d1.degrade(),
+                qspi_io2: pins.qspi_d2.degrade(),
+                qspi_io3: pins.qspi_d3.degrade(),
+                qspi_csn: pins.qspi_csn.degrade(),
+                qspi_sck: pins.qspi_sck.degrade(),
+            };
+            kernel::selftest::selftest(device.QSPI, qspi_pins, pins.led2)
+        };
+
+        // Show we're alive, until an app takes over the LED
+        let heartbeat = Heartbeat::new(pins.led1);
+        defmt::unwrap!(heartbeat_tick::spawn().ok());
+
         // Before we give away the USB peripheral, enable the relevant interrupts
         enable_usb_interrupts(&device.USBD);
 
@@ -92,15 +131,17 @@ mod app {
         let leak_uart = box_uart.leak();
         let to_uart: &'static mut dyn kernel::traits::Serial = leak_uart;
 
-        let machine = kernel::traits::Machine {
-            serial: to_uart,
-        };
+        let mut machine = kernel::traits::Machine::new(to_uart);
+        machine.set_reset_reason(reset_cause, exit_reason);
+        #[cfg(feature = "selftest")]
+        machine.set_flash_jedec_id(flash_jedec_id);
 
         (
             Shared {},
             Local {
                 usb_isr: isr,
                 machine,
+                heartbeat,
             },
             init::Monotonics(mono),
         )
@@ -113,7 +154,7 @@ mod app {
         if let Ok(()) = try_recv_syscall(|req| {
             machine.handle_syscall(req)
         }) {
-            // defmt::println!("Handled syscall!");
+            // log_debug!("Handled syscall!");
         }
     }
 
@@ -122,13 +163,21 @@ mod app {
         cx.local.usb_isr.poll();
     }
 
+    // Above the syscall handler, so a long (but legitimate) syscall, like a
+    // sleep, doesn't stop the heartbeat. A wedged interrupt handler does.
+    #[task(local = [heartbeat], priority = 2)]
+    fn heartbeat_tick(cx: heartbeat_tick::Context) {
+        cx.local.heartbeat.tick();
+        heartbeat_tick::spawn_after(HEARTBEAT_TICK_MS.millis()).ok();
+    }
+
     // TODO: I am currently polling the syscall interfaces in the idle function,
     // since I don't have syscalls yet. In the future, the `machine` will be given
     // to the SWI handler, and idle will basically just launch a program. I think.
     // Maybe idle will use SWIs too.
     #[idle]
     fn idle(_cx: idle::Context) -> ! {
-        defmt::println!("Hello, world!");
+        log_info!("Hello, world!");
 
         let timer = GlobalRollingTimer::default();
         let start = timer.get_ticks();
@@ -136,19 +185,47 @@ mod app {
         // Wait, to allow RTT to attach
         while timer.millis_since(start) < 100 { }
 
-        defmt::println!("!!! - ENTERING USERSPACE - !!!");
-
-        let rh = validate_header(DEFAULT_IMAGE).unwrap();
-        let pws = rh.oc_flash_setup(DEFAULT_IMAGE);
+        log_info!("!!! - ENTERING USERSPACE - !!!");
+
+        let rh = match validate_header(DEFAULT_IMAGE) {
+            Ok(rh) => rh,
+            Err(()) => {
+                // Nothing to run. Don't panic, so the USB link (serviced by
+                // interrupts) stays up, and the device stays reachable.
+                log_warn!("No valid application image! Idling.");
+                loop {
+                    cortex_m::asm::wfi();
+                }
+            }
+        };
+        let pws = oc_flash_setup(&rh, DEFAULT_IMAGE);
 
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
-        unsafe {
-            letsago(pws.stack_start, pws.entry_point);
+        let err = match launch_app(pws) {
+            Ok(never) => match never {},
+            Err(err) => err,
+        };
+        log_error!("Can't launch application: {}. Idling.", err);
+        loop {
+            cortex_m::asm::wfi();
         }
     }
 }
 
+use core::convert::Infallible;
+use kernel::loader::{PartingWords, LaunchError};
+
+/// Jump into a loaded application, if its stack and entry point look sane.
+/// Only returns if they don't.
+fn launch_app(pws: PartingWords) -> Result<Infallible, LaunchError> {
+    pws.check()?;
+
+    unsafe {
+        letsago(pws.stack_start, pws.entry_point);
+    }
+}
+
 use core::arch::asm;
 use cortex_m::register::{control, psp};
 
@@ -1,5 +1,19 @@
 // RTIC Monotonic impl for the 32-bit timers
+//
+// The timers only count to 2^32 microseconds (about 71 minutes) before
+// wrapping around. To give a time that doesn't wrap in practice, the
+// monotonic's interrupt also fires on every wraparound (using compare
+// channel 2), and counts them as the upper 32 bits of a 64-bit time. See
+// `now64()`.
+//
+// Capture/compare channels:
+//
+// * 0: The next RTIC timer queue deadline
+// * 1: Captures the time for `now()`
+// * 2: Fixed at 0, to catch the wraparound
+// * 3: Captures the time for `now64()`
 pub use fugit::{self, ExtU32};
+use core::sync::atomic::{AtomicU32, Ordering};
 use nrf52840_hal::pac::{timer0, TIMER0, TIMER1, TIMER2};
 use rtic_monotonic::Monotonic;
 
@@ -11,6 +25,7 @@ impl<T: Instance32> MonoTimer<T> {
             |w| unsafe { w.prescaler().bits(4) }, // 1 MHz
         );
         timer.bitmode.write(|w| w.bitmode()._32bit());
+        timer.cc[2].write(|w| unsafe { w.cc().bits(0) });
         MonoTimer(timer)
     }
 }
@@ -19,12 +34,25 @@ impl<T: Instance32> Monotonic for MonoTimer<T> {
     type Instant = fugit::TimerInstantU32<1_000_000>;
     type Duration = fugit::TimerDurationU32<1_000_000>;
 
+    // The interrupt must keep running to count wraparounds, even when no
+    // tasks are scheduled
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
     unsafe fn reset(&mut self) {
-        self.0.intenset.modify(|_, w| w.compare0().set());
+        self.0.intenset.modify(|_, w| w.compare0().set().compare2().set());
         self.0.tasks_clear.write(|w| w.bits(1));
+        self.0.events_compare[2].write(|w| w);
+        T::overflows().store(0, Ordering::SeqCst);
         self.0.tasks_start.write(|w| w.bits(1));
     }
 
+    fn on_interrupt(&mut self) {
+        if self.0.events_compare[2].read().bits() != 0 {
+            self.0.events_compare[2].write(|w| w);
+            T::overflows().fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
     #[inline(always)]
     fn now(&mut self) -> Self::Instant {
         self.0.tasks_capture[1].write(|w| unsafe { w.bits(1) });
@@ -45,7 +73,58 @@ impl<T: Instance32> Monotonic for MonoTimer<T> {
     }
 }
 
-pub trait Instance32: core::ops::Deref<Target = timer0::RegisterBlock> {}
-impl Instance32 for TIMER0 {}
-impl Instance32 for TIMER1 {}
-impl Instance32 for TIMER2 {}
+/// The current time of the TIMER0 monotonic, in microseconds since boot.
+///
+/// Unlike the monotonic's own (32-bit) `now()`, this doesn't wrap around
+/// in practice. It can be called from any context, including with interrupts
+/// disabled, or at a higher priority than the monotonic's interrupt.
+pub fn now64() -> u64 {
+    // SAFETY: Only capture channel 3 is written, and nothing else uses it
+    let timer = unsafe { &*TIMER0::ptr() };
+
+    loop {
+        let hi = TIMER0::overflows().load(Ordering::SeqCst);
+        timer.tasks_capture[3].write(|w| unsafe { w.bits(1) });
+        let lo = timer.cc[3].read().bits();
+        let pending = timer.events_compare[2].read().bits() != 0;
+
+        // The interrupt counted a wraparound while we were reading. Start
+        // over, so `hi` and `lo` are consistent.
+        if TIMER0::overflows().load(Ordering::SeqCst) != hi {
+            continue;
+        }
+
+        // A wraparound happened, but the interrupt hasn't counted it yet.
+        // It only applies to `lo` if we read `lo` after the wraparound: it
+        // will be small, as the interrupt is long overdue otherwise.
+        let hi = if pending && (lo < (1 << 31)) { hi + 1 } else { hi };
+
+        return ((hi as u64) << 32) | (lo as u64);
+    }
+}
+
+pub trait Instance32: core::ops::Deref<Target = timer0::RegisterBlock> {
+    /// The number of times this timer has wrapped around, while used as a
+    /// [MonoTimer]
+    fn overflows() -> &'static AtomicU32;
+}
+
+static TIMER0_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+static TIMER1_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+static TIMER2_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+impl Instance32 for TIMER0 {
+    fn overflows() -> &'static AtomicU32 {
+        &TIMER0_OVERFLOWS
+    }
+}
+impl Instance32 for TIMER1 {
+    fn overflows() -> &'static AtomicU32 {
+        &TIMER1_OVERFLOWS
+    }
+}
+impl Instance32 for TIMER2 {
+    fn overflows() -> &'static AtomicU32 {
+        &TIMER2_OVERFLOWS
+    }
+}
@@ -7,8 +7,8 @@
 //! Chip: GD25Q16
 //! note: This chip defaults to ???
 //!
-//! Manufacturer ID: 0x??
-//! Device ID: 0x??_????
+//! Manufacturer ID: 0xC8
+//! Device ID: 0x40_15
 
 use core::{sync::atomic::Ordering, task::Poll, ops::Deref};
 pub use byte_slab::ManagedArcSlab;
@@ -25,6 +25,8 @@ pub struct FlashChunk<'a, const CT: usize, const SZ: usize> {
 }
 
 use cassette::futures::poll_fn;
+use groundhog::RollingTimer;
+use groundhog_nrf52::GlobalRollingTimer;
 use nrf52840_hal::{
     gpio::{Disconnected, Pin, Port},
     pac::{P0, P1, QSPI},
@@ -44,16 +46,69 @@ pub struct QspiPins {
 pub struct Qspi {
     _pins: QspiPins,
     periph: QSPI,
+    jedec_id: [u8; 3],
+    /// Set once an operation timed out. The peripheral is disabled by then,
+    /// and all further operations fail with `Error::FlashTimeout`.
+    dead: bool,
+}
+
+/// The JEDEC ID of the GD25Q16: manufacturer, memory type, capacity
+pub const GD25Q16_JEDEC_ID: [u8; 3] = [0xC8, 0x40, 0x15];
+
+// How long to wait for the flash before giving up, in microseconds. These
+// are the GD25Q16's worst case times, with some margin on top.
+const COMMAND_TIMEOUT_US: u32 = 10_000;
+const PAGE_PROGRAM_TIMEOUT_US: u32 = 5_000;
+const WRITE_STATUS_TIMEOUT_US: u32 = 50_000;
+const ERASE_4KB_TIMEOUT_US: u32 = 500_000;
+const ERASE_64KB_TIMEOUT_US: u32 = 2_000_000;
+const ERASE_ALL_TIMEOUT_US: u32 = 30_000_000;
+
+/// How long reading `len` bytes may take, in microseconds. At 16MHz, four
+/// lanes move 8 bytes per microsecond, so allow for much less than that.
+fn read_timeout_us(len: usize) -> u32 {
+    COMMAND_TIMEOUT_US + (len as u32 / 2)
+}
+
+/// How long writing `len` bytes may take, in microseconds. The peripheral
+/// programs them a page at a time.
+fn write_timeout_us(len: usize) -> u32 {
+    let pages = len.div_ceil(256) as u32;
+    COMMAND_TIMEOUT_US + (pages * PAGE_PROGRAM_TIMEOUT_US)
 }
 
 #[derive(defmt::Format)]
 pub enum Error {
     /// Address was not aligned properly
     Alignment,
+    /// A read's flash address wasn't word aligned
+    UnalignedAddress,
+    /// The flash chip didn't identify itself as a GD25Q16. All `0xFF`s (or
+    /// `0x00`s) usually mean there's no chip answering at all.
+    UnexpectedChip {
+        jedec_id: [u8; 3],
+    },
+    /// Reading back written data didn't match, e.g. because of stuck bits,
+    /// or because the region wasn't erased first. `addr` is the first
+    /// mismatching flash address.
+    WriteVerifyFailed {
+        addr: usize,
+    },
+    /// The flash didn't finish an operation in time, e.g. because the chip
+    /// is dead, or not wired up properly
+    FlashTimeout,
+    /// The flash's Quad Enable bit didn't stick after writing the status
+    /// registers, e.g. because they are write protected
+    QuadEnableFailed,
 }
 
 impl Qspi {
-    pub fn new(periph: QSPI, pins: QspiPins) -> Self {
+    /// Set up the peripheral, and check that the expected flash chip is
+    /// there. On failure, the peripheral is disabled again.
+    ///
+    /// Like `uninit()`, a failure consumes the peripheral and pins. They
+    /// can't be handed back, so the flash stays unusable until reset.
+    pub fn new(periph: QSPI, pins: QspiPins) -> Result<Self, Error> {
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
         periph.enable.write(|w| w.enable().disabled());
         unsafe {
@@ -147,22 +202,41 @@ impl Qspi {
             .tasks_activate
             .write(|w| w.tasks_activate().set_bit());
 
-        // Wait for the ready flag
-        while periph.events_ready.read().events_ready().bit_is_clear() {}
-
-        quad_enable(&periph);
+        // Wait for the ready flag, then check for the right chip before
+        // poking at its status registers
+        let checked = wait_ready(&periph, COMMAND_TIMEOUT_US)
+            .and_then(|()| read_jedec_id(&periph))
+            .and_then(|jedec_id| match jedec_id {
+                GD25Q16_JEDEC_ID => Ok(jedec_id),
+                _ => Err(Error::UnexpectedChip { jedec_id }),
+            })
+            .and_then(|jedec_id| quad_enable(&periph).map(|()| jedec_id));
+
+        let jedec_id = match checked {
+            Ok(jedec_id) => jedec_id,
+            Err(err) => {
+                periph.enable.write(|w| w.enable().disabled());
+                return Err(err);
+            }
+        };
 
         // Make sure no reads happen BEFORE the QSPI is enabled
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
-        Self {
+        Ok(Self {
             _pins: pins,
             periph,
-        }
+            jedec_id,
+            dead: false,
+        })
     }
 
 
     pub fn read_slice(&self, flash_addr: usize, len: usize) -> Result<&[u8], u32> {
+        // The memory mapped region is gone once the peripheral is disabled
+        if self.dead {
+            return Err(flash_addr as u32);
+        }
         if !(flash_addr < (16 * 1024 * 1024)) {
             return Err(flash_addr as u32);
         }
@@ -180,6 +254,12 @@ impl Qspi {
     }
 
     pub async fn read(&mut self, start: usize, dest: &mut [u8]) -> Result<(), Error> {
+        self.check_alive()?;
+
+        // EasyDMA can only write to RAM, a word at a time. See
+        // `HeapGuard::alloc_box_aligned()` for a buffer that always works.
+        debug_assert!(is_dma_capable(dest), "QSPI read buffer not in RAM, or misaligned");
+
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
         self.periph.read.dst.write(|w| unsafe { w.bits(dest.as_ptr() as u32) });
@@ -190,13 +270,56 @@ impl Qspi {
         self.periph.events_ready.reset();
         self.periph.tasks_readstart.write(|w| w.tasks_readstart().set_bit());
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
-        self.wait_done().await;
+        let res = self.wait_done(read_timeout_us(dest.len())).await;
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
+        res
+    }
+
+    /// Read `len` bytes starting at `start`, one chunk at a time, calling
+    /// `f(offset, chunk)` for each one, with `offset` relative to `start`.
+    ///
+    /// This allows processing large regions (e.g. a CRC check, or copying an
+    /// image) without a buffer for the whole region. The chunk size is the
+    /// size of `scratch`, which must be in RAM, word aligned, and a non-zero
+    /// multiple of 4 bytes, or this fails with `Error::Alignment`. The last
+    /// chunk may be shorter. `start` must be word aligned too.
+    pub async fn read_streaming<F>(
+        &mut self,
+        start: usize,
+        len: usize,
+        scratch: &mut [u8],
+        mut f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        // `read()` only checks the buffer with a debug assertion
+        if scratch.is_empty() || (scratch.len() % 4) != 0 || !is_dma_capable(scratch) {
+            return Err(Error::Alignment);
+        }
+        if start & 0x3 != 0 {
+            return Err(Error::UnalignedAddress);
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let amt = (len - offset).min(scratch.len());
+
+            // The peripheral only transfers whole words
+            let words = (amt + 3) & !3;
+            self.read(start + offset, &mut scratch[..words]).await?;
+
+            f(offset, &scratch[..amt]);
+            offset += amt;
+        }
+
         Ok(())
     }
 
     pub async fn write<'a, const CT: usize, const SZ: usize>(&mut self, data: FlashChunk<'a, CT, SZ>) -> Result<(), Error> {
+        self.check_alive()?;
+
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
         self.periph.write.dst.write(|w| unsafe { w.bits(data.addr as u32)});
@@ -207,15 +330,59 @@ impl Qspi {
         self.periph.events_ready.reset();
         self.periph.tasks_writestart.write(|w| w.tasks_writestart().set_bit());
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
-        self.wait_done().await;
+        let res = self.wait_done(write_timeout_us(data.data.len())).await;
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
         drop(data);
 
-        Ok(())
+        res
+    }
+
+    /// Like `write()`, but read the data back afterwards, and check that it
+    /// made it to the flash, using `scratch` as the read buffer (see
+    /// `read_streaming()` for its requirements, which also apply to
+    /// `data.addr`). These are checked before anything is written.
+    ///
+    /// Only the bytes in `data` are compared, so the (still erased) rest of
+    /// a page doesn't matter.
+    pub async fn write_verified<'a, const CT: usize, const SZ: usize>(
+        &mut self,
+        data: FlashChunk<'a, CT, SZ>,
+        scratch: &mut [u8],
+    ) -> Result<(), Error> {
+        let addr = data.addr;
+        if scratch.is_empty() || (scratch.len() % 4) != 0 || !is_dma_capable(scratch) {
+            return Err(Error::Alignment);
+        }
+        if addr & 0x3 != 0 {
+            return Err(Error::UnalignedAddress);
+        }
+
+        // Keep a handle on the data, as `write()` consumes its chunk
+        let expected = data.data.clone();
+        self.write(data).await?;
+
+        let mut mismatch = None;
+        self.read_streaming(addr, expected.len(), scratch, |offset, chunk| {
+            let want = &expected[offset..][..chunk.len()];
+            if mismatch.is_none() {
+                mismatch = chunk
+                    .iter()
+                    .zip(want.iter())
+                    .position(|(got, want)| got != want)
+                    .map(|pos| addr + offset + pos);
+            }
+        }).await?;
+
+        match mismatch {
+            None => Ok(()),
+            Some(addr) => Err(Error::WriteVerifyFailed { addr }),
+        }
     }
 
     pub async fn erase(&mut self, start: usize, len: EraseLength) -> Result<(), Error> {
+        self.check_alive()?;
+
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
         // Ensure alignment to page size
@@ -226,6 +393,12 @@ impl Qspi {
             _ => {}
         }
 
+        let timeout_us = match len {
+            EraseLength::_4KB => ERASE_4KB_TIMEOUT_US,
+            EraseLength::_64KB => ERASE_64KB_TIMEOUT_US,
+            EraseLength::ALL => ERASE_ALL_TIMEOUT_US,
+        };
+
         self.periph.erase.ptr.write(|w| unsafe { w.bits(start as u32) });
         self.periph.erase.len.write(|w| w.len().variant(len) );
 
@@ -233,20 +406,60 @@ impl Qspi {
         self.periph.events_ready.reset();
         self.periph.tasks_erasestart.write(|w| w.tasks_erasestart().set_bit());
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
-        self.wait_done().await;
+        let res = self.wait_done(timeout_us).await;
         core::sync::atomic::compiler_fence(Ordering::SeqCst);
 
-        Ok(())
+        res
     }
 
-    pub async fn wait_done(&self) {
-        poll_fn(|_| {
-            if self.periph.events_ready.read().events_ready().bit_is_clear() {
-                Poll::Pending
+    /// Wait for the current operation to finish, for at most `timeout_us`
+    /// microseconds.
+    ///
+    /// On a timeout, the operation is still in progress as far as the
+    /// peripheral is concerned, so it is deactivated and disabled, and all
+    /// later operations fail right away. The flash is unusable until reset.
+    pub async fn wait_done(&mut self, timeout_us: u32) -> Result<(), Error> {
+        let timer = GlobalRollingTimer::default();
+        let start = timer.get_ticks();
+
+        let res = poll_fn(|_| {
+            if self.periph.events_ready.read().events_ready().bit_is_set() {
+                Poll::Ready(Ok(()))
+            } else if timer.micros_since(start) >= timeout_us {
+                Poll::Ready(Err(Error::FlashTimeout))
             } else {
-                Poll::Ready(())
+                Poll::Pending
             }
-        }).await
+        }).await;
+
+        if res.is_err() {
+            core::sync::atomic::compiler_fence(Ordering::SeqCst);
+            self.periph.tasks_deactivate.write(|w| w.tasks_deactivate().set_bit());
+            self.periph.enable.write(|w| w.enable().disabled());
+            self.dead = true;
+        }
+
+        res
+    }
+
+    /// Fail fast if an earlier operation timed out, see `wait_done()`
+    fn check_alive(&self) -> Result<(), Error> {
+        match self.dead {
+            true => Err(Error::FlashTimeout),
+            false => Ok(()),
+        }
+    }
+
+    /// Read the JEDEC manufacturer and device ID of the flash chip, as
+    /// `[manufacturer, memory type, capacity]`.
+    pub fn read_jedec_id(&self) -> Result<[u8; 3], Error> {
+        read_jedec_id(&self.periph)
+    }
+
+    /// The JEDEC ID read when the flash was brought up in `new()`, without
+    /// talking to the chip again
+    pub fn jedec_id(&self) -> [u8; 3] {
+        self.jedec_id
     }
 
     pub fn uninit(self) {
@@ -261,7 +474,30 @@ impl Qspi {
     }
 }
 
-fn read_status_regs(periph: &QSPI) -> [u8; 2] {
+/// Is `buf` in RAM, and word aligned?
+fn is_dma_capable(buf: &[u8]) -> bool {
+    const RAM_START: usize = 0x2000_0000;
+    const RAM_END: usize = 0x2004_0000;
+
+    let start = buf.as_ptr() as usize;
+    let end = start + buf.len();
+    (start % 4 == 0) && (start >= RAM_START) && (end <= RAM_END)
+}
+
+/// Busy-wait for the ready event, for at most `timeout_us` microseconds
+fn wait_ready(periph: &QSPI, timeout_us: u32) -> Result<(), Error> {
+    let timer = GlobalRollingTimer::default();
+    let start = timer.get_ticks();
+
+    while periph.events_ready.read().events_ready().bit_is_clear() {
+        if timer.micros_since(start) >= timeout_us {
+            return Err(Error::FlashTimeout);
+        }
+    }
+    Ok(())
+}
+
+fn read_status_regs(periph: &QSPI) -> Result<[u8; 2], Error> {
 
     // Clear the "is ready" flag
     periph.events_ready.reset();
@@ -284,7 +520,7 @@ fn read_status_regs(periph: &QSPI) -> [u8; 2] {
             w
         });
 
-    while periph.events_ready.read().events_ready().bit_is_clear() { }
+    wait_ready(periph, COMMAND_TIMEOUT_US)?;
 
     let data = periph.cinstrdat0.read();
     // S7..S0
@@ -312,20 +548,48 @@ fn read_status_regs(periph: &QSPI) -> [u8; 2] {
             w
         });
 
-    while periph.events_ready.read().events_ready().bit_is_clear() { }
+    wait_ready(periph, COMMAND_TIMEOUT_US)?;
 
     let data = periph.cinstrdat0.read();
     // S15..S7
     let by_35 = data.byte0().bits();
 
-    [by_05, by_35]
+    Ok([by_05, by_35])
+}
+
+fn read_jedec_id(periph: &QSPI) -> Result<[u8; 3], Error> {
+    // Clear the "is ready" flag
+    periph.events_ready.reset();
+
+    periph
+        .cinstrdat0
+        .write(|w| unsafe { w.bits(0xFFFF_FFFF)});
+
+    periph
+        .cinstrconf
+        .write(|w| {
+            unsafe { w.opcode().bits(0x9F) };
+            w.length()._4b();
+            w.lio2().set_bit(); // ???
+            w.lio3().set_bit(); // ???
+            w.wipwait().set_bit();
+            w.wren().disable();
+            w.lfen().disable();
+            w.lfstop().clear_bit();
+            w
+        });
+
+    wait_ready(periph, COMMAND_TIMEOUT_US)?;
+
+    let data = periph.cinstrdat0.read();
+    Ok([data.byte0().bits(), data.byte1().bits(), data.byte2().bits()])
 }
 
 // Note: I don't think I need this, since the `cinstrconf` allows you to send
 // a write enable before a given command. Leaving it here for now - likely possible
 // to cull later.
 #[allow(dead_code)]
-fn write_enable(periph: &QSPI) {
+fn write_enable(periph: &QSPI) -> Result<(), Error> {
     // Clear the "is ready" flag
     periph.events_ready.reset();
 
@@ -343,14 +607,14 @@ fn write_enable(periph: &QSPI) {
             w
         });
 
-    while periph.events_ready.read().events_ready().bit_is_clear() { }
+    wait_ready(periph, COMMAND_TIMEOUT_US)
 }
 
-fn quad_enable(periph: &QSPI) {
+fn quad_enable(periph: &QSPI) -> Result<(), Error> {
     // Clear the "is ready" flag
     periph.events_ready.reset();
 
-    let status = read_status_regs(periph);
+    let status = read_status_regs(periph)?;
 
     periph
         .cinstrdat0
@@ -374,8 +638,12 @@ fn quad_enable(periph: &QSPI) {
             w
         });
 
-    while periph.events_ready.read().events_ready().bit_is_clear() { }
+    // Writing the status registers takes much longer than other commands
+    wait_ready(periph, WRITE_STATUS_TIMEOUT_US)?;
 
-    let status = read_status_regs(periph);
-    assert_eq!(status[1] & 0x02, 0x02, "QE bit not set?");
+    let status = read_status_regs(periph)?;
+    if (status[1] & 0x02) == 0 {
+        return Err(Error::QuadEnableFailed);
+    }
+    Ok(())
 }
@@ -0,0 +1,100 @@
+//! A quick check of the board's hardware, run early in `init`
+//!
+//! Bring-up faults (like unsoldered flash) otherwise only show up later, as
+//! confusing failures deep in the running system. Each subsystem is checked
+//! in turn, and reported over defmt. If any fails, the kernel stops, and
+//! blinks `led2` to say which one, instead of carrying on in a broken state.
+//!
+//! `led1` is exercised by the heartbeat (see `drivers::heartbeat`), which
+//! starts after the self-test passes.
+
+use embedded_hal::digital::v2::OutputPin;
+use groundhog::RollingTimer;
+use groundhog_nrf52::GlobalRollingTimer;
+use nrf52840_hal::{
+    gpio::{p1::P1_10, Disconnected, Level, Output, Pin, PushPull},
+    pac::QSPI,
+};
+use crate::{alloc::HEAP, qspi::{Qspi, QspiPins}};
+
+/// A subsystem checked by the self-test. On failure, `led2` blinks as many
+/// times as the subsystem's number, then pauses, over and over.
+#[derive(defmt::Format, Clone, Copy, PartialEq)]
+pub enum Subsystem {
+    Heap = 1,
+    Flash = 2,
+}
+
+/// Run the self-test. Only returns if everything passed, with the flash's
+/// JEDEC ID.
+///
+/// The QSPI flash is only probed (and left disabled afterwards), as the
+/// kernel doesn't use it yet. `GlobalRollingTimer` must already be running.
+pub fn selftest(qspi: QSPI, qspi_pins: QspiPins, led2: P1_10<Disconnected>) -> [u8; 3] {
+    let mut led = led2.into_push_pull_output(Level::Low).degrade();
+
+    // Lamp test: if this doesn't light up, that's the problem
+    led.set_high().ok();
+    delay_ms(100);
+    led.set_low().ok();
+
+    match check_heap().and_then(|()| check_flash(qspi, qspi_pins)) {
+        Ok(jedec_id) => {
+            log_info!("Self-test passed.");
+            jedec_id
+        }
+        Err(sub) => {
+            log_error!("Self-test FAILED: {}. Halting.", sub);
+            blink_forever(led, sub);
+        }
+    }
+}
+
+fn check_heap() -> Result<(), Subsystem> {
+    // `try_lock()` only succeeds once the heap is initialized
+    let free = HEAP.try_lock().map(|hg| hg.free_space()).unwrap_or(0);
+    if free == 0 {
+        log_error!("Self-test: heap not initialized, or empty");
+        return Err(Subsystem::Heap);
+    }
+
+    log_debug!("Self-test: heap ok, {=usize} bytes free", free);
+    Ok(())
+}
+
+fn check_flash(qspi: QSPI, qspi_pins: QspiPins) -> Result<[u8; 3], Subsystem> {
+    let checked = Qspi::new(qspi, qspi_pins).map(|flash| {
+        let jedec_id = flash.jedec_id();
+        flash.uninit();
+        jedec_id
+    });
+
+    match checked {
+        Ok(jedec_id) => {
+            log_debug!("Self-test: flash ok, JEDEC ID {=[u8]:02X}", jedec_id);
+            Ok(jedec_id)
+        }
+        Err(err) => {
+            log_error!("Self-test: flash error {}", err);
+            Err(Subsystem::Flash)
+        }
+    }
+}
+
+fn blink_forever(mut led: Pin<Output<PushPull>>, sub: Subsystem) -> ! {
+    loop {
+        for _ in 0..(sub as u8) {
+            led.set_high().ok();
+            delay_ms(200);
+            led.set_low().ok();
+            delay_ms(200);
+        }
+        delay_ms(1000);
+    }
+}
+
+fn delay_ms(ms: u32) {
+    let timer = GlobalRollingTimer::default();
+    let start = timer.get_ticks();
+    while timer.millis_since(start) < ms { }
+}
@@ -3,7 +3,7 @@
 
 use core::sync::atomic::Ordering;
 use common::{SYSCALL_IN_PTR, SYSCALL_IN_LEN, SYSCALL_OUT_PTR, SYSCALL_OUT_LEN};
-use common::{SysCallRequest, SysCallSuccess};
+use common::{SysCallRequest, SysCallSuccess, SysCallError, SYSCALL_ABI_VERSION, split_abi_version};
 
 // TODO: This is really only a "kernel" thing...
 // DON'T call this in the svc handler! Userspace should clean up after
@@ -25,7 +25,7 @@ where
     // of the syscall. Userspace has two, since it has a different view of
     // the data. We need to be rid of BOTH before we are done handling the
     // syscall.
-    F: FnOnce(SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, ()>
+    F: FnOnce(SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, SysCallError>
 {
     let inp_ptr = SYSCALL_IN_PTR.load(Ordering::SeqCst) as *const u8;
     let inp_len = SYSCALL_IN_LEN.load(Ordering::SeqCst);
@@ -40,39 +40,43 @@ where
         return Err(());
     }
 
-    // Okay, seems good, let's call the handler
     let inp_slice = unsafe { core::slice::from_raw_parts(inp_ptr, inp_len) };
-    let request = match postcard::from_bytes(inp_slice) {
-        Ok(req) => req,
-        Err(_) => {
-            // ANGERY
-            SYSCALL_OUT_LEN.store(0, Ordering::SeqCst);
-            return Err(());
-        },
-    };
+    let out_slice = unsafe { core::slice::from_raw_parts_mut(out_ptr, out_len) };
 
-    let response = match hdlr(request) {
-        Ok(resp) => resp,
+    // Every request and response starts with the ABI version. If the app
+    // doesn't speak our version, we can't trust our reading of the request
+    // at all. Reply with just our version, so the app can tell this apart
+    // from any other failure.
+    out_slice[0] = SYSCALL_ABI_VERSION;
+    let inp_slice = match split_abi_version(inp_slice) {
+        Ok(inp) => inp,
         Err(_) => {
-            // ANGERY
-            SYSCALL_OUT_LEN.store(0, Ordering::SeqCst);
+            SYSCALL_OUT_LEN.store(1, Ordering::SeqCst);
             return Err(());
-        },
+        }
     };
 
-    let out_slice = unsafe { core::slice::from_raw_parts_mut(out_ptr, out_len) };
+    // Okay, seems good, let's call the handler. Whatever happens from here
+    // on, tell the app why.
+    let response = match postcard::from_bytes(inp_slice) {
+        Ok(req) => hdlr(req),
+        Err(_) => Err(SysCallError::Malformed),
+    };
+    let handled = response.is_ok();
 
-    let used = match postcard::to_slice(&response, out_slice) {
-        Ok(ser) => ser.len(),
+    let used = match postcard::to_slice(&response, &mut out_slice[1..]) {
+        Ok(ser) => ser.len() + 1,
         Err(_) => {
-            // ANGERY
-            SYSCALL_OUT_LEN.store(0, Ordering::SeqCst);
-            return Err(());
+            // The error is much smaller than any success, so this should fit
+            let err: Result<SysCallSuccess<'_>, _> = Err(SysCallError::ResponseTooLarge);
+            match postcard::to_slice(&err, &mut out_slice[1..]) {
+                Ok(ser) => ser.len() + 1,
+                Err(_) => 0,
+            }
         },
     };
 
-    // Happy!
     SYSCALL_OUT_LEN.store(used, Ordering::SeqCst);
 
-    Ok(())
+    if handled { Ok(()) } else { Err(()) }
 }
@@ -1,15 +1,92 @@
-use common::{SysCallRequest, SysCallSuccess};
+use common::{SysCallRequest, SysCallSuccess, SysCallError, PortMode, PortPriority, PortInfo, PortStats, WakeSource, LinkState, LinkStats, StatusLed, LineCoding, ResetCause, ExitReason};
 use groundhog_nrf52::GlobalRollingTimer;
 use groundhog::RollingTimer;
+use nrf52840_hal::pac::FICR;
+use heapless::{LinearMap, Vec};
+use crate::alloc::{HeapArray, HEAP};
+use crate::monotonic::now64;
+
+/// Why the serial driver refused a request
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum SerialError {
+    /// The port isn't registered
+    NoSuchPort,
+    /// The port is already registered, or another port holds what it asked for
+    PortInUse,
+    /// The port number is out of range
+    InvalidPort,
+    /// No room to register another port
+    TooManyPorts,
+    /// The port doesn't support this request (e.g. it has its own interface)
+    Unsupported,
+}
+
+impl From<SerialError> for SysCallError {
+    fn from(err: SerialError) -> Self {
+        match err {
+            SerialError::NoSuchPort => SysCallError::NoSuchPort,
+            SerialError::PortInUse => SysCallError::PortInUse,
+            SerialError::InvalidPort => SysCallError::InvalidArgument,
+            SerialError::TooManyPorts => SysCallError::LimitReached,
+            SerialError::Unsupported => SysCallError::Serial,
+        }
+    }
+}
 
 pub trait Serial: Send {
-    fn register_port(&mut self, port: u16) -> Result<(), ()>;
-    fn release_port(&mut self, port: u16) -> Result<(), ()>;
+    fn register_port(&mut self, port: u16) -> Result<(), SerialError>;
+    fn release_port(&mut self, port: u16) -> Result<(), SerialError>;
+
+    // Release every port that can be released (all but port 0, and any
+    // dedicated ports). On success: the number of ports released.
+    fn release_all(&mut self) -> usize;
+
+    // Fill `out` with the registered ports. On success: the number filled,
+    // which is less than the number of ports if `out` is too short.
+    fn list_ports(&mut self, out: &mut [PortInfo]) -> usize;
+    fn set_port_mode(&mut self, port: u16, mode: PortMode) -> Result<(), SerialError>;
+    fn set_port_priority(&mut self, port: u16, priority: PortPriority) -> Result<(), SerialError>;
+
+    // Limit `port` to sending `bytes_per_sec` on average. 0 removes the limit.
+    fn set_port_rate(&mut self, port: u16, bytes_per_sec: u32) -> Result<(), SerialError>;
     fn process(&mut self);
 
+    // The current state of the link to the host, and the number of times
+    // it was suspended since boot.
+    fn link_state(&self) -> (LinkState, u32);
+
+    // Error counters for the multiplexed link, since boot
+    fn link_stats(&self) -> LinkStats;
+
+    // The data waiting on `port`, and its send budget if it is rate limited
+    fn port_stats(&mut self, port: u16) -> Result<PortStats, SerialError>;
+
+    // The line coding the host last set on `port`'s interface (or the
+    // default, if it hasn't set one).
+    fn line_coding(&mut self, port: u16) -> Result<LineCoding, SerialError>;
+
+    // On success: The number of bytes currently ready to be received on `port`
+    fn available(&mut self, port: u16) -> Result<usize, SerialError>;
+
+    // On success: A lower bound on the number of bytes `send` would accept
+    // on `port` right now. 0 if the outgoing queue is full.
+    fn send_space(&mut self, port: u16) -> Result<usize, SerialError>;
+
+    // Block until there is at least one byte ready to be received on `port`,
+    // or until `deadline` (in `now64()` ticks) passes, if there is one.
+    // On success: The number of bytes ready (> 0), or `None` on timeout
+    fn wait_data(&mut self, port: u16, deadline: Option<u64>) -> Result<Option<usize>, SerialError>;
+
     // On success: The valid received part (<= buf.len()). Can be &[] (if no bytes)
-    // On error: TODO
-    fn recv<'a>(&mut self, port: u16, buf: &'a mut [u8]) -> Result<&'a mut [u8], ()>;
+    fn recv<'a>(&mut self, port: u16, buf: &'a mut [u8]) -> Result<&'a mut [u8], SerialError>;
+
+    // Like `recv`, but only takes what is already queued for `port`, without
+    // calling `process()` to decode new input first.
+    fn recv_nowait<'a>(&mut self, port: u16, buf: &'a mut [u8]) -> Result<&'a mut [u8], SerialError>;
+
+    // On success: The next whole queued chunk for `port`, if any, handed
+    // over without copying.
+    fn recv_owned(&mut self, port: u16) -> Result<Option<HeapArray<u8>>, SerialError>;
 
     // On success: All bytes were sent/enqueued.
     // On error: the portion of bytes that were NOT sent (the remainder). (<= buf.len()).
@@ -19,20 +96,163 @@ pub trait Serial: Send {
 
 // pub trait SendSerial: Serial + Send {}
 
+/// A handler for `SysCallRequest::Vendor` requests with one `id`.
+///
+/// This lets a board integrator add syscalls for their own peripherals (like
+/// a display) without touching the syscall enums: register a handler with
+/// `Machine::register_vendor()` in `init`, and apps reach it with
+/// `porcelain::system::vendor()`. The payload and response formats are up
+/// to the handler and its apps.
+pub trait VendorHandler: Send {
+    // Handle `payload`, writing any response into `out`. This runs in the
+    // syscall handler, so don't block for long.
+    // On success: The number of bytes of `out` used (<= out.len())
+    fn handle(&mut self, payload: &[u8], out: &mut [u8]) -> Result<usize, ()>;
+}
+
+/// The maximum number of received buffers lent to userspace at once
+pub const MAX_LENT: usize = 8;
+
+/// The maximum number of pools handed to userspace at once
+pub const MAX_POOLS: usize = 4;
+
+/// The maximum number of registered `VendorHandler`s
+pub const MAX_VENDOR: usize = 4;
+
+/// The application RAM, as `(start, size)`, from the linker script
+fn app_region() -> (u32, u32) {
+    extern "C" {
+        static _app_start: u8;
+        static _app_len: u8;
+    }
+
+    // These are absolute symbols: their "address" is their value. Taking
+    // the address (without making a reference) doesn't need `unsafe`.
+    (
+        core::ptr::addr_of!(_app_start) as u32,
+        core::ptr::addr_of!(_app_len) as u32,
+    )
+}
+
 pub struct Machine {
     pub serial: &'static mut dyn Serial,
     // TODO: port router?
     // TODO: flash manager?
+
+    // Buffers handed to userspace by `SerialReceiveOwned`. The kernel still
+    // owns these, and frees them when userspace gives them back with
+    // `SerialReleaseOwned`.
+    lent: Vec<HeapArray<u8>, MAX_LENT>,
+
+    // Working buffers allocated for userspace by `AllocPool`. Like `lent`,
+    // the kernel still owns these, until `FreePool` (or the app exits).
+    pools: Vec<HeapArray<u8>, MAX_POOLS>,
+
+    // Board-specific syscalls, by vendor id
+    vendor: LinearMap<u16, &'static mut dyn VendorHandler, MAX_VENDOR>,
+
+    // Why we (re)booted, taken at init before anything could clear it
+    reset_cause: ResetCause,
+    exit_reason: Option<ExitReason>,
+
+    // The QSPI flash's JEDEC ID, if the self-test brought it up
+    flash_jedec_id: Option<[u8; 3]>,
 }
 
 impl Machine {
-    pub fn handle_syscall<'a>(&mut self, req: SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, ()> {
+    pub fn new(serial: &'static mut dyn Serial) -> Self {
+        Self {
+            serial,
+            lent: Vec::new(),
+            pools: Vec::new(),
+            vendor: LinearMap::new(),
+            reset_cause: ResetCause::PowerOn,
+            exit_reason: None,
+            flash_jedec_id: None,
+        }
+    }
+
+    /// Route `Vendor` requests with `id` to `handler`. Fails if `id` already
+    /// has a handler, or `MAX_VENDOR` handlers are registered.
+    pub fn register_vendor(&mut self, id: u16, handler: &'static mut dyn VendorHandler) -> Result<(), ()> {
+        if self.vendor.contains_key(&id) {
+            return Err(());
+        }
+        self.vendor.insert(id, handler).map_err(drop)?;
+        Ok(())
+    }
+
+    /// Record why we booted, as reported by the `ResetReason` syscall. See
+    /// `take_reset_cause()` and `take_exit_reason()`.
+    pub fn set_reset_reason(&mut self, cause: ResetCause, exit: Option<ExitReason>) {
+        self.reset_cause = cause;
+        self.exit_reason = exit;
+    }
+
+    /// Record the QSPI flash's JEDEC ID, as reported by the `DeviceIds`
+    /// syscall. See `selftest::selftest()`.
+    pub fn set_flash_jedec_id(&mut self, jedec_id: [u8; 3]) {
+        self.flash_jedec_id = Some(jedec_id);
+    }
+
+    fn exit(&mut self, reason: ExitReason) -> ! {
+        // The app is gone, so nothing it was handed can still be in use
+        self.pools.clear();
+        self.lent.clear();
+        crate::drivers::heartbeat::set_control(StatusLed::Heartbeat);
+        crate::app_exit(reason)
+    }
+
+    pub fn handle_syscall<'a>(&mut self, req: SysCallRequest<'a>) -> Result<SysCallSuccess<'a>, SysCallError> {
+        // Userspace can put anything in a slice's pointer and length. Make
+        // sure any slice it hands us is within its own RAM (or a pool we
+        // gave it) before touching it, so it can't use us to read or write
+        // anything else. Buffers we lent out can be read (e.g. to echo
+        // them back), but not written.
+        let (app_start, app_len) = app_region();
+        let pools = &self.pools;
+        let lent = &self.lent;
+        let usable = |is_within: &dyn Fn(u32, u32) -> bool| {
+            is_within(app_start, app_len)
+                || pools.iter().any(|p| is_within(p.as_ptr() as u32, p.len() as u32))
+        };
+        let readable = |is_within: &dyn Fn(u32, u32) -> bool| {
+            usable(is_within)
+                || lent.iter().any(|l| is_within(l.as_ptr() as u32, l.len() as u32))
+        };
+        let in_app = match &req {
+            SysCallRequest::SerialReceive { dest_buf, .. } => usable(&|s, n| dest_buf.is_within(s, n)),
+            SysCallRequest::SerialReceiveNoWait { dest_buf, .. } => usable(&|s, n| dest_buf.is_within(s, n)),
+            SysCallRequest::SerialListPorts { dest_buf } => usable(&|s, n| dest_buf.is_within(s, n)),
+            SysCallRequest::SerialSend { src_buf, .. } => readable(&|s, n| src_buf.is_within(s, n)),
+            SysCallRequest::Vendor { payload, dest_buf, .. } => {
+                usable(&|s, n| payload.is_within(s, n))
+                    && usable(&|s, n| dest_buf.is_within(s, n))
+                    && !dest_buf.overlaps(payload)
+            }
+            // Checked against the buffers we lent out, below
+            _ => true,
+        };
+        if !in_app {
+            log_warn!("Rejected a syscall slice outside of app RAM and pools, or overlapping another");
+            return Err(SysCallError::InvalidSlice);
+        }
+
         match req {
             SysCallRequest::SerialReceive { port, dest_buf } => {
                 let dest_buf = unsafe { dest_buf.to_slice_mut() };
                 let used = self.serial.recv(port, dest_buf)?;
                 Ok(SysCallSuccess::DataReceived { dest_buf: used.into() })
             },
+            SysCallRequest::SerialReceiveNoWait { port, dest_buf } => {
+                let dest_buf = unsafe { dest_buf.to_slice_mut() };
+                let used = self.serial.recv_nowait(port, dest_buf)?;
+                Ok(SysCallSuccess::DataReceived { dest_buf: used.into() })
+            },
+            SysCallRequest::SerialPump => {
+                self.serial.process();
+                Ok(SysCallSuccess::Pumped)
+            },
             SysCallRequest::SerialSend { port, src_buf } => {
                 let src_buf = unsafe { src_buf.to_slice() };
                 match self.serial.send(port, src_buf) {
@@ -44,16 +264,225 @@ impl Machine {
                     },
                 }
             },
+            SysCallRequest::SerialAvailable { port } => {
+                let bytes = self.serial.available(port)?;
+                Ok(SysCallSuccess::DataAvailable { bytes: bytes as u32 })
+            },
+            SysCallRequest::SerialSendSpace { port } => {
+                let bytes = self.serial.send_space(port)?;
+                Ok(SysCallSuccess::SendSpace { bytes: bytes as u32 })
+            },
+            SysCallRequest::SerialWaitData { port, timeout_ticks } => {
+                let deadline = timeout_ticks.map(|ticks| now64() + u64::from(ticks));
+                let bytes = self.serial.wait_data(port, deadline)
+                    ?
+                    .ok_or(SysCallError::Timeout)?;
+                Ok(SysCallSuccess::DataAvailable { bytes: bytes as u32 })
+            },
+            SysCallRequest::SerialReceiveOwned { port } => {
+                // Don't take a message off the queue if we can't lend it out
+                if self.lent.is_full() {
+                    return Err(SysCallError::LimitReached);
+                }
+
+                let buf = match self.serial.recv_owned(port)? {
+                    Some(msg) => msg,
+                    None => return Ok(SysCallSuccess::OwnedDataReceived { buf: None }),
+                };
+
+                // The heap allocation doesn't move when the HeapArray does,
+                // so the slice stays valid while it sits in `lent`.
+                let sli: &'a [u8] = unsafe { core::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+                self.lent.push(buf).ok();
+                Ok(SysCallSuccess::OwnedDataReceived { buf: Some(sli.into()) })
+            },
+            SysCallRequest::SerialReleaseOwned { buf } => {
+                // Only trust the pointer if it's within one we actually lent out
+                let pos = self.lent
+                    .iter()
+                    .position(|l| buf.is_within(l.as_ptr() as u32, l.len() as u32))
+                    .ok_or(SysCallError::InvalidSlice)?;
+                let buf = unsafe { buf.to_slice() };
+                if buf.as_ptr() != self.lent[pos].as_ptr() {
+                    return Err(SysCallError::InvalidSlice);
+                }
+                drop(self.lent.swap_remove(pos));
+                Ok(SysCallSuccess::OwnedDataReleased)
+            },
+            SysCallRequest::SerialListPorts { dest_buf } => {
+                let dest_buf = unsafe { dest_buf.to_slice_mut() };
+                // More than any driver registers
+                let mut infos = [PortInfo::default(); 16];
+                let count = self.serial.list_ports(&mut infos);
+
+                // Entries that don't fit are left out
+                let mut used = 0;
+                for info in infos[..count].iter() {
+                    match postcard::to_slice(info, &mut dest_buf[used..]) {
+                        Ok(enc) => used += enc.len(),
+                        Err(_) => break,
+                    }
+                }
+                let used = &mut dest_buf[..used];
+                Ok(SysCallSuccess::PortsListed { dest_buf: used.into() })
+            },
+            SysCallRequest::SerialReleaseAll => {
+                let count = self.serial.release_all();
+                Ok(SysCallSuccess::AllReleased { count: count as u32 })
+            },
+            SysCallRequest::Vendor { id, payload, dest_buf } => {
+                let handler = self.vendor.get_mut(&id).ok_or(SysCallError::NoVendorHandler)?;
+                let payload = unsafe { payload.to_slice() };
+                let dest_buf = unsafe { dest_buf.to_slice_mut() };
+
+                let used = handler.handle(payload, dest_buf).map_err(|_| SysCallError::Vendor)?;
+                let used = dest_buf.get_mut(..used).ok_or(SysCallError::Vendor)?;
+                Ok(SysCallSuccess::VendorHandled { dest_buf: used.into() })
+            },
+            SysCallRequest::SetStatusLed { control } => {
+                crate::drivers::heartbeat::set_control(control);
+                Ok(SysCallSuccess::StatusLedSet)
+            },
+            SysCallRequest::SerialGetLineCoding { port } => {
+                let coding = self.serial.line_coding(port)?;
+                Ok(SysCallSuccess::LineCoding { coding })
+            },
+            SysCallRequest::SerialLinkState => {
+                let (state, suspends) = self.serial.link_state();
+                Ok(SysCallSuccess::LinkState { state, suspends })
+            },
+            SysCallRequest::SerialLinkStats => {
+                Ok(SysCallSuccess::LinkStats { stats: self.serial.link_stats() })
+            },
+            SysCallRequest::SerialPortStats { port } => {
+                let stats = self.serial.port_stats(port)?;
+                Ok(SysCallSuccess::PortStats { stats })
+            },
             SysCallRequest::SerialOpenPort { port } => {
                 self.serial.register_port(port)?;
                 Ok(SysCallSuccess::PortOpened)
             },
+            SysCallRequest::SerialSetPortPriority { port, priority } => {
+                self.serial.set_port_priority(port, priority)?;
+                Ok(SysCallSuccess::PortPrioritySet)
+            },
+            SysCallRequest::SerialSetPortRate { port, bytes_per_sec } => {
+                self.serial.set_port_rate(port, bytes_per_sec)?;
+                Ok(SysCallSuccess::PortRateSet)
+            },
+            SysCallRequest::SerialSetPortMode { port, mode } => {
+                self.serial.set_port_mode(port, mode)?;
+                Ok(SysCallSuccess::PortModeSet)
+            },
             SysCallRequest::SleepMicros { us } => {
                 let timer = GlobalRollingTimer::default();
                 let start = timer.get_ticks();
                 while timer.micros_since(start) <= us { }
                 Ok(SysCallSuccess::SleptMicros { us })
             }
+            SysCallRequest::SleepUntil { tick } => {
+                // The 64-bit time doesn't wrap, so deadlines in the past
+                // simply return at once
+                let mut now = now64();
+                while now < tick {
+                    now = now64();
+                }
+                Ok(SysCallSuccess::SleptUntil { tick: now })
+            }
+            SysCallRequest::SleepService { port, us } => {
+                // Like `SleepMicros`, but keep decoding incoming data while
+                // we wait, and stop early once something shows up on `port`
+                let timer = GlobalRollingTimer::default();
+                let start = timer.get_ticks();
+                loop {
+                    let available = self.serial.available(port)?;
+                    let slept = timer.micros_since(start);
+                    if available != 0 || slept >= us {
+                        return Ok(SysCallSuccess::SleptService { us: slept, available: available as u32 });
+                    }
+                }
+            }
+            SysCallRequest::StopwatchStart => {
+                // The handle is just the time the stopwatch started at
+                Ok(SysCallSuccess::StopwatchStarted { handle: now64() })
+            }
+            SysCallRequest::StopwatchLap { handle } => {
+                let elapsed_ticks = now64().saturating_sub(handle);
+                Ok(SysCallSuccess::StopwatchLapped { elapsed_ticks })
+            }
+            SysCallRequest::KernelVersion => {
+                Ok(SysCallSuccess::VersionInfo {
+                    major: crate::version::major(),
+                    minor: crate::version::minor(),
+                    patch: crate::version::patch(),
+                    git_hash: crate::version::GIT_HASH.as_bytes().into(),
+                    build_timestamp: crate::version::build_timestamp(),
+                })
+            }
+            SysCallRequest::LowPower { wake_on: WakeSource::Interrupt } => {
+                // We are in the SVCall handler, so any higher priority
+                // interrupt (like USB) wakes us, and is serviced before we
+                // get to return.
+                cortex_m::asm::dsb();
+                cortex_m::asm::wfi();
+                Ok(SysCallSuccess::Woke)
+            }
+            SysCallRequest::AllocPool { bytes } => {
+                if bytes == 0 {
+                    return Err(SysCallError::InvalidArgument);
+                }
+                if self.pools.is_full() {
+                    return Err(SysCallError::LimitReached);
+                }
+
+                let mut pool = HEAP
+                    .try_lock()
+                    .ok_or(SysCallError::HeapFull)?
+                    .alloc_box_array(0u8, bytes as usize)
+                    .map_err(|_| SysCallError::HeapFull)?;
+
+                // As with `lent`, the allocation doesn't move when the
+                // HeapArray does, so the slice stays valid while it sits in
+                // `pools`.
+                let sli: &'a mut [u8] = unsafe { core::slice::from_raw_parts_mut(pool.as_mut_ptr(), pool.len()) };
+                self.pools.push(pool).ok();
+                Ok(SysCallSuccess::PoolAllocated { buf: sli.into() })
+            }
+            SysCallRequest::FreePool { buf } => {
+                // Only trust the pointer if it's one of our pools
+                let pos = self.pools
+                    .iter()
+                    .position(|p| buf.is_within(p.as_ptr() as u32, p.len() as u32))
+                    .ok_or(SysCallError::InvalidSlice)?;
+                let buf = unsafe { buf.to_slice() };
+                if buf.as_ptr() != self.pools[pos].as_ptr() {
+                    return Err(SysCallError::InvalidSlice);
+                }
+                drop(self.pools.swap_remove(pos));
+                Ok(SysCallSuccess::PoolFreed)
+            }
+            SysCallRequest::Exit { reason } => self.exit(reason),
+            // The default image is the only one the kernel boots, so a reboot
+            // loads it fresh
+            SysCallRequest::BootDefault => self.exit(ExitReason::Reboot),
+            SysCallRequest::ResetReason => {
+                Ok(SysCallSuccess::ResetReason { cause: self.reset_cause, exit: self.exit_reason })
+            }
+            SysCallRequest::SetLogLevel { level } => {
+                crate::log::set_level(level);
+                Ok(SysCallSuccess::LogLevelSet)
+            }
+            SysCallRequest::DeviceIds => {
+                // SAFETY: The FICR is read-only, and never modified at runtime
+                let ficr = unsafe { &*FICR::ptr() };
+                let lo = ficr.deviceid[0].read().bits() as u64;
+                let hi = ficr.deviceid[1].read().bits() as u64;
+
+                Ok(SysCallSuccess::DeviceIds {
+                    device_id: (hi << 32) | lo,
+                    flash_jedec_id: self.flash_jedec_id,
+                })
+            }
         }
     }
 }
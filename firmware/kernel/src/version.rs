@@ -0,0 +1,21 @@
+//! Build information about the running kernel
+
+/// The short git hash this kernel was built from, or "unknown"
+pub static GIT_HASH: &str = env!("KERNEL_GIT_HASH");
+
+/// Build time of this kernel, in seconds since the unix epoch
+pub fn build_timestamp() -> u32 {
+    env!("KERNEL_BUILD_TIMESTAMP").parse().unwrap_or(0)
+}
+
+pub fn major() -> u16 {
+    env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0)
+}
+
+pub fn minor() -> u16 {
+    env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0)
+}
+
+pub fn patch() -> u16 {
+    env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0)
+}
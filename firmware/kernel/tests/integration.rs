@@ -7,10 +7,29 @@ use kernel as _; // memory layout + panic handler
 // feature)
 #[defmt_test::tests]
 mod tests {
-    use defmt::assert;
+    use bbqueue::BBBuffer;
+    use defmt::{assert, assert_eq};
+    use kernel::drivers::usb_serial::enqueue_frames;
 
     #[test]
     fn it_works() {
         assert!(true)
     }
+
+    #[test]
+    fn full_send_queue_returns_remainder() {
+        static QUEUE: BBBuffer<64> = BBBuffer::new();
+        let (mut prod, _cons) = defmt::unwrap!(QUEUE.try_split().ok());
+        let data = [0xAC; 128];
+
+        // Only part of the data fits, the rest should be handed back
+        let rem = defmt::unwrap!(enqueue_frames(&mut prod, 1, &data).err());
+        assert!(!rem.is_empty());
+        assert!(rem.len() < data.len());
+
+        // Now the queue is completely full. This must not panic, and
+        // should give back everything.
+        let rem = defmt::unwrap!(enqueue_frames(&mut prod, 1, &data).err());
+        assert_eq!(rem.len(), data.len());
+    }
 }
@@ -13,10 +13,18 @@ extern "Rust" {
 pub static __ENTRY_POINT: unsafe fn() -> ! = entry;
 
 use core::panic::PanicInfo;
-use core::sync::atomic::{self, Ordering};
+use core::sync::atomic::{self, AtomicBool, Ordering};
+use common::{ExitReason, porcelain::system};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    // Let the kernel know we crashed. If reporting it panics too, give up.
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        system::exit(ExitReason::Panic);
+    }
+
     loop {
         atomic::compiler_fence(Ordering::SeqCst);
     }
@@ -36,6 +36,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .map_err(|_| "Error: failed to create port")?;
 
     let mut carry = Vec::new();
+    let mut fragments: HashMap<u16, Vec<u8>> = HashMap::new();
 
     port.set_timeout(Duration::from_millis(10)).ok();
 
@@ -151,9 +152,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         while let Some(pos) = carry.iter().position(|b| *b == 0) {
             let new_chunk = carry.split_off(pos + 1);
             if let Ok(msg) = Message::decode_in_place(&mut carry) {
-                if let Some(hdl) = manager.workers.get_mut(&msg.port) {
-                    println!("Got {} bytes from port {}", msg.data.len(), msg.port);
-                    hdl.out.send(msg.data.to_vec()).ok();
+                let port = msg.logical_port();
+
+                // Collect fragments until we have the whole logical message
+                let partial = fragments.entry(port).or_insert_with(Vec::new);
+                partial.extend_from_slice(msg.data);
+
+                if !msg.is_continued() {
+                    let whole = fragments.remove(&port).unwrap_or_default();
+                    if let Some(hdl) = manager.workers.get_mut(&port) {
+                        println!("Got {} bytes from port {}", whole.len(), port);
+                        hdl.out.send(whole).ok();
+                    }
                 }
             } else {
                 println!("Bad decode!");